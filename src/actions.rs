@@ -0,0 +1,732 @@
+use crate::errors::{RollErrorKind, WakeBotError};
+use crate::rolls::{parse_rolls, split_label, split_message_label};
+use crate::storage::{Action, ActionScope, Storage, WakeBotDbError};
+use std::collections::HashMap;
+use std::future::Future;
+use std::ops::Range;
+use std::pin::Pin;
+
+// How many actions !action list shows at a time
+const ACTIONS_PER_PAGE: usize = 20;
+
+// Reads the arguments after "!action list", which are an optional filter and then an optional page number.
+// A lone number is taken as the page.
+pub fn parse_list_args<'a>(args: &[&'a str]) -> Option<(Option<&'a str>, usize)> {
+    match args {
+        [] => Some((None, 1)),
+        [arg] => match arg.parse::<usize>() {
+            Ok(page) => Some((None, page)),
+            Err(_) => Some((Some(arg), 1)),
+        },
+        [filter, page] => Some((Some(filter), page.parse().ok()?)),
+        _ => None,
+    }
+}
+
+// A filter ending in '*' matches the start of names, anything else can match anywhere in them. Case is ignored.
+pub fn filter_actions(actions: Vec<Action>, filter: Option<&str>) -> Vec<Action> {
+    let filter = match filter {
+        Some(filter) => filter.to_lowercase(),
+        None => return actions,
+    };
+    actions
+        .into_iter()
+        .filter(|action| {
+            let name = action.name.to_lowercase();
+            match filter.strip_suffix('*') {
+                Some(prefix) => name.starts_with(prefix),
+                None => name.contains(&filter),
+            }
+        })
+        .collect()
+}
+
+fn scope_label(scope: &ActionScope) -> &'static str {
+    match scope {
+        ActionScope::Server { .. } => "server",
+        ActionScope::Personal { .. } => "personal",
+        ActionScope::Global => "global",
+    }
+}
+
+// Mentioning the owner would ping them, so this only says whether the action belongs to whoever asked
+pub fn format_action(action: &Action, user_id: &str) -> String {
+    let owner = if action.owner_id == user_id {
+        ", yours"
+    } else {
+        ""
+    };
+    format!(
+        "`{}` ({}{}): `!{}`",
+        action.name,
+        scope_label(&action.scope),
+        owner,
+        action.roll
+    )
+}
+
+pub fn format_action_list(actions: &[Action], filter: Option<&str>, page: usize) -> String {
+    if actions.is_empty() {
+        return match filter {
+            Some(filter) => format!("No actions match '{}'.", filter),
+            None => String::from("There are no actions yet. Add one with '!action <name> <roll>'."),
+        };
+    }
+    let pages = actions.len().div_ceil(ACTIONS_PER_PAGE);
+    if page == 0 || page > pages {
+        return format!(
+            "There {} only {} page{} of actions.",
+            if pages == 1 { "is" } else { "are" },
+            pages,
+            if pages == 1 { "" } else { "s" }
+        );
+    }
+    let lines = actions
+        .iter()
+        .skip((page - 1) * ACTIONS_PER_PAGE)
+        .take(ACTIONS_PER_PAGE)
+        .map(|action| match action.scope {
+            // Server actions are the usual case, so only the others are called out
+            ActionScope::Server { .. } => format!("`{}`: `!{}`", action.name, action.roll),
+            _ => format!(
+                "`{}`: `!{}` ({})",
+                action.name,
+                action.roll,
+                scope_label(&action.scope)
+            ),
+        })
+        .collect::<Vec<String>>()
+        .join("\n");
+    let mut result = format!(
+        "**Actions** (page {} of {}, {} total)\n{}",
+        page,
+        pages,
+        actions.len(),
+        lines
+    );
+    if page < pages {
+        result += &format!(
+            "\nUse '!action list {}{}' for more.",
+            filter
+                .map(|filter| format!("{} ", filter))
+                .unwrap_or_default(),
+            page + 1
+        );
+    }
+    result
+}
+
+// A parameter in a saved roll. "{0}" and "{1}" are filled by position, "{bonus}" by name, and "{bonus=5}"
+// falls back to 5 when no value is given.
+struct Placeholder<'a> {
+    span: Range<usize>,
+    name: &'a str,
+    default: Option<&'a str>,
+}
+
+fn find_placeholders(roll: &str) -> Vec<Placeholder<'_>> {
+    let mut placeholders = vec![];
+    let mut search_from = 0;
+    while let Some(offset) = roll[search_from..].find('{') {
+        let start = search_from + offset;
+        search_from = start + 1;
+        let end = match roll[start..].find('}') {
+            Some(length) => start + length + 1,
+            None => break,
+        };
+        let (name, default) = match roll[start + 1..end - 1].split_once('=') {
+            Some((name, default)) => (name.trim(), Some(default.trim())),
+            None => (roll[start + 1..end - 1].trim(), None),
+        };
+        let is_named = name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+            && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+        // Custom dice like "d{1,3,5}" use braces too, so a number straight after a 'd' is left alone
+        let is_positional = !name.is_empty()
+            && name.chars().all(|c| c.is_ascii_digit())
+            && !roll[..start].trim_end().ends_with(['d', 'D']);
+        if is_named || is_positional {
+            placeholders.push(Placeholder {
+                span: start..end,
+                name,
+                default,
+            });
+            search_from = end;
+        }
+    }
+    placeholders
+}
+
+// Values can refer to actions and variables too, which are only looked up once the action is used
+fn check_argument(name: &str, value: &str) -> Result<(), WakeBotError> {
+    parse_rolls(&stub_references(value))
+        .map(|_| ())
+        .map_err(|e| WakeBotError::InvalidArgument {
+            name: String::from(name),
+            error: Box::new(e),
+        })
+}
+
+// Brackets anything other than a plain number, so that something like "{bonus}*2" with "1d4+1" still works
+// out as expected
+fn as_fragment(value: &str) -> String {
+    if value.chars().all(|c| c.is_ascii_digit()) {
+        String::from(value)
+    } else {
+        format!("({})", value)
+    }
+}
+
+// Swaps each placeholder for its value
+fn substitute<'a>(
+    roll: &'a str,
+    placeholders: &[Placeholder<'a>],
+    mut value_for: impl FnMut(&Placeholder<'a>) -> Result<&'a str, WakeBotError>,
+) -> Result<String, WakeBotError> {
+    let mut result = String::new();
+    let mut last_end = 0;
+    for placeholder in placeholders {
+        let value = value_for(placeholder)?;
+        check_argument(placeholder.name, value)?;
+        result += &roll[last_end..placeholder.span.start];
+        result += &as_fragment(value);
+        last_end = placeholder.span.end;
+    }
+    result += &roll[last_end..];
+    Ok(result)
+}
+
+// Fills an action's placeholders from the arguments it was used with, e.g. "3" for "{0}" or "bonus=7" for
+// "{bonus}". Every value has to be a valid roll on its own, so arguments can't change the rest of the roll.
+// The label is left as it was written.
+pub fn fill_placeholders<'a>(roll: &'a str, args: &[&'a str]) -> Result<String, WakeBotError> {
+    let (without_label, _) = split_message_label(roll);
+    let placeholders = find_placeholders(without_label);
+    let mut positional = vec![];
+    let mut named = HashMap::new();
+    for arg in args {
+        match arg.split_once('=') {
+            Some((name, value)) => {
+                if !placeholders
+                    .iter()
+                    .any(|placeholder| placeholder.name == name)
+                {
+                    return Err(WakeBotError::UnknownArgument(String::from(name)));
+                }
+                named.insert(name, value);
+            }
+            None => positional.push(*arg),
+        }
+    }
+    let positional_count = placeholders
+        .iter()
+        .filter_map(|placeholder| placeholder.name.parse::<usize>().ok())
+        .map(|index| index + 1)
+        .max()
+        .unwrap_or(0);
+    if positional.len() > positional_count {
+        return Err(WakeBotError::TooManyArguments(positional_count));
+    }
+    let filled = substitute(without_label, &placeholders, |placeholder| {
+        let from_position = placeholder
+            .name
+            .parse::<usize>()
+            .ok()
+            .and_then(|index| positional.get(index).copied());
+        named
+            .get(placeholder.name)
+            .copied()
+            .or(from_position)
+            .or(placeholder.default)
+            .ok_or_else(|| WakeBotError::MissingArgument(String::from(placeholder.name)))
+    })?;
+    Ok(filled + &roll[without_label.len()..])
+}
+
+// Fills every placeholder with its default, or 1 if it has none, and every reference with 1, so a roll can
+// be checked before it's saved. The label is left as it is.
+pub fn fill_defaults(roll: &str) -> Result<String, WakeBotError> {
+    let (without_label, _) = split_message_label(roll);
+    let filled = substitute(
+        without_label,
+        &find_placeholders(without_label),
+        |placeholder| Ok(placeholder.default.unwrap_or("1")),
+    )?;
+    Ok(stub_references(&filled) + &roll[without_label.len()..])
+}
+
+// How many actions and variables deep references can go, e.g. an action using an action that uses a
+// variable is two deep
+const MAX_REFERENCE_DEPTH: usize = 10;
+// How many references can be looked up for one roll, counting every use of each one, and how long the roll
+// can get once they're expanded. Without these a few actions each using the next several times could
+// grow exponentially.
+const MAX_REFERENCE_LOOKUPS: usize = 100;
+const MAX_EXPANDED_LENGTH: usize = 2000;
+
+// A reference in a roll to a variable set with !set, like "$str_mod", or to either a variable or an action,
+// like "@str_mod", with the user's variable winning if both exist. Names can't contain '-', which would
+// read as a minus sign.
+struct Reference<'a> {
+    span: Range<usize>,
+    name: &'a str,
+    variable_only: bool,
+}
+
+impl Reference<'_> {
+    // How the reference was written in the roll
+    fn written(&self) -> String {
+        format!(
+            "{}{}",
+            if self.variable_only { "$" } else { "@" },
+            self.name
+        )
+    }
+}
+
+fn find_references(roll: &str) -> Vec<Reference<'_>> {
+    let mut references = vec![];
+    for (start, sigil) in roll.match_indices(['@', '$']) {
+        let name_start = start + sigil.len();
+        let name_end = roll[name_start..]
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+            .map_or(roll.len(), |length| name_start + length);
+        if name_end > name_start {
+            references.push(Reference {
+                span: start..name_end,
+                name: &roll[name_start..name_end],
+                variable_only: sigil == "$",
+            });
+        }
+    }
+    references
+}
+
+pub fn has_references(roll: &str) -> bool {
+    !find_references(roll).is_empty()
+}
+
+fn stub_references(roll: &str) -> String {
+    let mut result = String::new();
+    let mut last_end = 0;
+    for reference in find_references(roll) {
+        result += &roll[last_end..reference.span.start];
+        result += "1";
+        last_end = reference.span.end;
+    }
+    result += &roll[last_end..];
+    result
+}
+
+// Finds what a reference points to, returning how to show it, e.g. "$str_mod" if it turned out to be a
+// variable, along with the roll it stands for
+async fn resolve_reference(
+    storage: &dyn Storage,
+    guild: &str,
+    user_id: &str,
+    reference: &Reference<'_>,
+) -> Result<(String, String), WakeBotError> {
+    let written = reference.written();
+    let lookup_failed = || WakeBotError::Message(format!("Couldn't look up '{}'", written));
+    match storage.get_variable(guild, user_id, reference.name).await {
+        Ok(Some(value)) => return Ok((format!("${}", reference.name), value)),
+        Ok(None) if reference.variable_only => {
+            return Err(WakeBotError::UnknownReference(written.clone()))
+        }
+        Ok(None) => {}
+        Err(_) => return Err(lookup_failed()),
+    }
+    match storage.get_action(guild, user_id, reference.name).await {
+        // A referenced action can't be given arguments, so its placeholders need defaults. Its label is
+        // dropped, as it couldn't be spliced into the middle of another roll.
+        Ok(action) => fill_placeholders(split_label(&action.roll).0, &[])
+            .map(|roll| (format!("@{}", reference.name), roll))
+            .map_err(|e| WakeBotError::InvalidReference {
+                name: written.clone(),
+                error: Box::new(e),
+            }),
+        Err(WakeBotDbError::NotFound(_)) => Err(WakeBotError::UnknownReference(written.clone())),
+        Err(_) => Err(lookup_failed()),
+    }
+}
+
+// What's been followed so far while expanding a roll. The chain holds every reference being followed, so
+// one that leads back to itself can be caught.
+struct Expansion {
+    chain: Vec<String>,
+    lookups: usize,
+}
+
+// Swaps every reference in a roll for what it refers to, following references inside those in turn
+fn expand<'a>(
+    storage: &'a dyn Storage,
+    guild: &'a str,
+    user_id: &'a str,
+    roll: &'a str,
+    expansion: &'a mut Expansion,
+) -> Pin<Box<dyn Future<Output = Result<String, WakeBotError>> + Send + 'a>> {
+    Box::pin(async move {
+        let mut result = String::new();
+        let mut last_end = 0;
+        for reference in find_references(roll) {
+            if expansion.chain.len() > MAX_REFERENCE_DEPTH {
+                return Err(WakeBotError::ReferencesTooDeep(MAX_REFERENCE_DEPTH));
+            }
+            expansion.lookups += 1;
+            if expansion.lookups > MAX_REFERENCE_LOOKUPS {
+                return Err(WakeBotError::roll(
+                    RollErrorKind::TooManyReferences(MAX_REFERENCE_LOOKUPS),
+                    reference.span,
+                ));
+            }
+            let (shown_as, value) = resolve_reference(storage, guild, user_id, &reference).await?;
+            if let Some(start) = expansion.chain.iter().position(|name| *name == shown_as) {
+                let mut cycle = expansion.chain[start..].to_vec();
+                cycle.push(shown_as);
+                return Err(WakeBotError::ReferenceCycle(cycle));
+            }
+            expansion.chain.push(shown_as);
+            // Going over the budget is pointed at the reference in this roll that led to it, so by the end
+            // it points into the roll the user will be shown
+            let expanded = expand(storage, guild, user_id, &value, expansion)
+                .await
+                .map_err(|e| match e {
+                    WakeBotError::Roll {
+                        kind:
+                            kind @ (RollErrorKind::TooManyReferences(_)
+                            | RollErrorKind::ExpandedTooLong(_)),
+                        ..
+                    } => WakeBotError::roll(kind, reference.span.clone()),
+                    e => e,
+                })?;
+            let shown_as = expansion.chain.pop().unwrap_or_default();
+            parse_rolls(&expanded).map_err(|e| WakeBotError::InvalidReference {
+                name: shown_as,
+                error: Box::new(e),
+            })?;
+            result += &roll[last_end..reference.span.start];
+            result += &as_fragment(&expanded);
+            last_end = reference.span.end;
+            if result.len() > MAX_EXPANDED_LENGTH {
+                return Err(WakeBotError::roll(
+                    RollErrorKind::ExpandedTooLong(MAX_EXPANDED_LENGTH),
+                    reference.span,
+                ));
+            }
+        }
+        result += &roll[last_end..];
+        Ok(result)
+    })
+}
+
+// Expands the actions and variables an action's roll refers to, so a variable like a character's modifier
+// only has to be changed in one place. Actions are looked up as the user would see them with !action. The
+// label is left as it was written, so a '$' or '@' in it isn't taken for a reference.
+pub async fn expand_references(
+    storage: &dyn Storage,
+    guild: &str,
+    user_id: &str,
+    action_name: &str,
+    roll: &str,
+) -> Result<String, WakeBotError> {
+    let mut expansion = Expansion {
+        chain: vec![format!("@{}", action_name)],
+        lookups: 0,
+    };
+    let (without_label, _) = split_message_label(roll);
+    let expanded = expand(storage, guild, user_id, without_label, &mut expansion).await?;
+    Ok(expanded + &roll[without_label.len()..])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::MemoryStorage;
+    use crate::storage::tests::server;
+
+    async fn storage_with(actions: &[(&str, &str)], variables: &[(&str, &str)]) -> MemoryStorage {
+        let storage = MemoryStorage::default();
+        for (name, roll) in actions {
+            storage
+                .add_or_update_action(&Action::new(name, roll, server(), "alice"))
+                .await
+                .unwrap();
+        }
+        for (name, value) in variables {
+            storage
+                .set_variable("guild", "alice", name, value)
+                .await
+                .unwrap();
+        }
+        storage
+    }
+
+    async fn expand_roll(storage: &MemoryStorage, roll: &str) -> Result<String, WakeBotError> {
+        expand_references(storage, "guild", "alice", "test", roll).await
+    }
+
+    fn actions_named(names: &[&str]) -> Vec<Action> {
+        names
+            .iter()
+            .map(|name| Action::new(name, "1d20", server(), "alice"))
+            .collect()
+    }
+
+    #[test]
+    fn reads_list_filter_and_page() {
+        assert_eq!(parse_list_args(&[]), Some((None, 1)));
+        assert_eq!(parse_list_args(&["2"]), Some((None, 2)));
+        assert_eq!(parse_list_args(&["att*"]), Some((Some("att*"), 1)));
+        assert_eq!(parse_list_args(&["att*", "3"]), Some((Some("att*"), 3)));
+        assert_eq!(parse_list_args(&["att*", "next"]), None);
+        assert_eq!(parse_list_args(&["a", "1", "b"]), None);
+    }
+
+    #[test]
+    fn filters_by_prefix_or_anywhere_in_the_name() {
+        let names = |actions: Vec<Action>| {
+            actions
+                .into_iter()
+                .map(|action| action.name)
+                .collect::<Vec<String>>()
+        };
+        let actions = actions_named(&["Attack", "sneak_attack", "heal"]);
+        assert_eq!(
+            names(filter_actions(actions.clone(), Some("att*"))),
+            ["Attack"]
+        );
+        assert_eq!(
+            names(filter_actions(actions.clone(), Some("ATTACK"))),
+            ["Attack", "sneak_attack"]
+        );
+        assert_eq!(names(filter_actions(actions, None)).len(), 3);
+    }
+
+    #[test]
+    fn pages_through_actions() {
+        let names: Vec<String> = (0..ACTIONS_PER_PAGE + 5)
+            .map(|i| format!("a{:02}", i))
+            .collect();
+        let actions = actions_named(&names.iter().map(String::as_str).collect::<Vec<&str>>());
+        let first = format_action_list(&actions, Some("a*"), 1);
+        assert!(first.starts_with("**Actions** (page 1 of 2, 25 total)\n`a00`: `!1d20`"));
+        assert!(first.ends_with("`a19`: `!1d20`\nUse '!action list a* 2' for more."));
+        let second = format_action_list(&actions, None, 2);
+        assert_eq!(second.lines().count(), 6);
+        assert!(second.ends_with("`a24`: `!1d20`"));
+        assert_eq!(
+            format_action_list(&actions, None, 3),
+            "There are only 2 pages of actions."
+        );
+        assert_eq!(
+            format_action_list(&actions[..1], None, 0),
+            "There is only 1 page of actions."
+        );
+        assert_eq!(
+            format_action_list(&[], Some("x"), 1),
+            "No actions match 'x'."
+        );
+    }
+
+    #[test]
+    fn fills_positional_placeholders() {
+        assert_eq!(fill_placeholders("1d20+{0}+5", &["3"]).unwrap(), "1d20+3+5");
+        assert_eq!(
+            fill_placeholders("{0}d6+{1}", &["2", "1d4"]).unwrap(),
+            "2d6+(1d4)"
+        );
+    }
+
+    #[test]
+    fn fills_named_placeholders_and_defaults() {
+        let roll = "1d20+{bonus=5}+{extra}";
+        assert_eq!(
+            fill_placeholders(roll, &["bonus=7", "extra=1d4"]).unwrap(),
+            "1d20+7+(1d4)"
+        );
+        assert_eq!(fill_placeholders(roll, &["extra=2"]).unwrap(), "1d20+5+2");
+        assert_eq!(fill_defaults(roll).unwrap(), "1d20+5+1");
+        assert_eq!(
+            fill_defaults("1d20+{0}+@str_mod # for {target=the orc} @ $5").unwrap(),
+            "1d20+1+1 # for {target=the orc} @ $5"
+        );
+    }
+
+    #[test]
+    fn leaves_custom_dice_alone() {
+        assert_eq!(
+            fill_placeholders("1d{1,3,5}+2d{5}+{0}", &["4"]).unwrap(),
+            "1d{1,3,5}+2d{5}+4"
+        );
+    }
+
+    #[test]
+    fn reports_bad_arguments() {
+        assert_eq!(
+            fill_placeholders("1d20+{0}+{bonus}", &["3"]),
+            Err(WakeBotError::MissingArgument(String::from("bonus")))
+        );
+        assert_eq!(
+            fill_placeholders("1d20+{0}", &["3", "4"]),
+            Err(WakeBotError::TooManyArguments(1))
+        );
+        assert_eq!(
+            fill_placeholders("1d20+{bonus}", &["bogus=1"]),
+            Err(WakeBotError::UnknownArgument(String::from("bogus")))
+        );
+    }
+
+    #[test]
+    fn arguments_cannot_break_out_of_their_brackets() {
+        for args in [["1)+(1", "bonus=2"], ["3", "bonus=1)+(1"]] {
+            let error = fill_placeholders("1d20+{0}*{bonus}", &args).unwrap_err();
+            assert!(
+                matches!(error, WakeBotError::InvalidArgument { .. }),
+                "{:?}",
+                error
+            );
+        }
+        assert!(matches!(
+            fill_defaults("1d20+{bonus=1)+(1}"),
+            Err(WakeBotError::InvalidArgument { .. })
+        ));
+    }
+
+    #[test]
+    fn finds_references() {
+        assert!(has_references("@attack+@damage"));
+        assert!(has_references("1d20+$str_mod"));
+        assert!(!has_references("1d20+5"));
+        assert_eq!(stub_references("1d20+@str_mod+$prof"), "1d20+1+1");
+    }
+
+    #[tokio::test]
+    async fn expands_actions_and_variables() {
+        let storage = storage_with(
+            &[("str_mod", "1d6"), ("damage", "!1d8+@str_mod")],
+            &[("str_mod", "3"), ("prof", "1d4")],
+        )
+        .await;
+        // The variable wins over the action with the same name
+        assert_eq!(
+            expand_roll(&storage, "1d20+@str_mod+$prof").await.unwrap(),
+            "1d20+3+(1d4)"
+        );
+        assert_eq!(
+            expand_roll(&storage, "@damage*2").await.unwrap(),
+            "(1d8+3)*2"
+        );
+    }
+
+    #[tokio::test]
+    async fn reports_unknown_references() {
+        let storage = storage_with(&[("attack", "1d20+5")], &[]).await;
+        assert_eq!(
+            expand_roll(&storage, "1d20+$attack").await,
+            Err(WakeBotError::UnknownReference(String::from("$attack")))
+        );
+        assert_eq!(
+            expand_roll(&storage, "1d20+@missing").await,
+            Err(WakeBotError::UnknownReference(String::from("@missing")))
+        );
+    }
+
+    #[tokio::test]
+    async fn drops_labels_from_referenced_actions() {
+        let storage = storage_with(&[("perception", "!1d20+5 # Perception")], &[]).await;
+        assert_eq!(
+            expand_roll(&storage, "@perception+2").await.unwrap(),
+            "(1d20+5)+2"
+        );
+    }
+
+    #[tokio::test]
+    async fn leaves_labels_alone() {
+        let storage = storage_with(&[], &[("str_mod", "3")]).await;
+        let roll = "1d20+{0}+@str_mod # costs $5 {0}";
+        let filled = fill_placeholders(roll, &["2"]).unwrap();
+        assert_eq!(filled, "1d20+2+@str_mod # costs $5 {0}");
+        assert_eq!(
+            expand_roll(&storage, &filled).await.unwrap(),
+            "1d20+2+3 # costs $5 {0}"
+        );
+        assert_eq!(
+            expand_roll(&storage, "3#1d20+$str_mod; 1d6 # uses @str_mod")
+                .await
+                .unwrap(),
+            "3#1d20+3; 1d6 # uses @str_mod"
+        );
+    }
+
+    #[tokio::test]
+    async fn catches_reference_cycles() {
+        let storage = storage_with(&[("a", "1d20+@b"), ("b", "@a")], &[]).await;
+        assert_eq!(
+            expand_roll(&storage, "@a").await,
+            Err(WakeBotError::ReferenceCycle(
+                ["@a", "@b", "@a"].map(String::from).to_vec()
+            ))
+        );
+    }
+
+    #[tokio::test]
+    async fn limits_reference_depth() {
+        let names: Vec<String> = (0..=MAX_REFERENCE_DEPTH + 1)
+            .map(|i| format!("d{}", i))
+            .collect();
+        let mut actions: Vec<(&str, String)> = names
+            .windows(2)
+            .map(|pair| (pair[0].as_str(), format!("@{}+1", pair[1])))
+            .collect();
+        actions.push((names.last().unwrap(), String::from("1")));
+        let actions: Vec<(&str, &str)> = actions
+            .iter()
+            .map(|(name, roll)| (*name, roll.as_str()))
+            .collect();
+        let storage = storage_with(&actions, &[]).await;
+        assert_eq!(
+            expand_roll(&storage, "@d0").await,
+            Err(WakeBotError::ReferencesTooDeep(MAX_REFERENCE_DEPTH))
+        );
+        assert_eq!(expand_roll(&storage, "@d9").await.unwrap(), "((1+1)+1)");
+    }
+
+    #[tokio::test]
+    async fn limits_how_far_references_grow() {
+        // Each action uses the next eight times, which would be 8^8 lookups in all
+        let rolls: Vec<String> = (1..8)
+            .map(|i| vec![format!("@a{}", i + 1); 8].join("+"))
+            .chain([String::from("1")])
+            .collect();
+        let names: Vec<String> = (1..=8).map(|i| format!("a{}", i)).collect();
+        let actions: Vec<(&str, &str)> = names
+            .iter()
+            .zip(&rolls)
+            .map(|(name, roll)| (name.as_str(), roll.as_str()))
+            .collect();
+        let storage = storage_with(&actions, &[]).await;
+        assert_eq!(
+            expand_roll(&storage, "1d20+@a1").await,
+            Err(WakeBotError::roll(
+                RollErrorKind::TooManyReferences(MAX_REFERENCE_LOOKUPS),
+                5..8
+            ))
+        );
+
+        let long_roll = ["1"; 200].join("+");
+        let storage = storage_with(&[("long", &long_roll)], &[]).await;
+        let error = expand_roll(&storage, &["@long"; 8].join("+"))
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(
+                error,
+                WakeBotError::Roll {
+                    kind: RollErrorKind::ExpandedTooLong(MAX_EXPANDED_LENGTH),
+                    ..
+                }
+            ),
+            "{:?}",
+            error
+        );
+    }
+}
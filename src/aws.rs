@@ -1,15 +1,17 @@
-use crate::errors::WakeBotError;
+use crate::luck::LuckStats;
+use crate::storage::{
+    action_not_found, roll_not_found, Action, ActionScope, RollRecord, Storage, WakeBotDbError,
+};
+use async_trait::async_trait;
 use aws_sdk_dynamodb::{
     config::Credentials,
-    error::SdkError,
-    operation::{
-        delete_item::{DeleteItemError, DeleteItemOutput},
-        get_item::GetItemError,
-        put_item::{PutItemError, PutItemOutput},
+    types::{
+        AttributeDefinition, AttributeValue, BillingMode, KeySchemaElement, KeyType,
+        ScalarAttributeType,
     },
-    types::AttributeValue,
     Client,
 };
+use std::collections::HashMap;
 
 pub async fn create_aws_client(credentials: Credentials) -> Client {
     let config = aws_config::from_env()
@@ -24,94 +26,565 @@ pub fn create_credentials_provider(access_key: &str, secret_key: &str) -> Creden
     Credentials::new(access_key, secret_key, None, None, "actions-provider")
 }
 
-pub struct Action<'a> {
-    pub name: &'a str,
-    pub roll: &'a str,
+const ACTIONS_TABLE: &str = "actions";
+// The roll log and luck stats grow with every roll, so each has a table of its own, keyed by "name" like the
+// actions table. That keeps scanning for actions from getting slower as more rolls are made. These two are
+// created on startup if they're missing, see create_tables.
+const ROLLS_TABLE: &str = "rolls";
+const LUCK_TABLE: &str = "luck";
+
+// Everything else is kept in the actions table, keyed by "name". Actions are keyed by their scope, except
+// for global ones which are keyed by their bare name, and everything else is stored under a key that can't
+// clash with action names, such as "crit:user:<id>" or "var:<guild>:<user id>:<name>".
+//
+// Roll records are keyed by their ID and luck stats by their scope. Luck stats keep each running total in an
+// attribute of its own, like "d20_rolled", so rolls can be added to them in a single update.
+pub struct DynamoDbStorage {
+    client: Client,
 }
 
-pub async fn add_or_update_action<'a>(
-    client: &Client,
-    action: &Action<'a>,
-) -> Result<PutItemOutput, SdkError<PutItemError>> {
-    let name_av = AttributeValue::S(String::from(action.name));
-    // Remove prepended ! as we want to get rid of those
-    let roll_av = AttributeValue::S(if action.roll.starts_with("!") {
-        String::from(&action.roll[1..])
-    } else {
-        String::from(action.roll)
-    });
-    let request = client
-        .put_item()
-        .table_name("actions")
-        .item("name", name_av)
-        .item("roll", roll_av);
-    request.send().await
+impl DynamoDbStorage {
+    pub fn new(client: Client) -> Self {
+        DynamoDbStorage { client }
+    }
+
+    // Creates the rolls and luck tables if they don't exist yet, so deployments from before they were split
+    // out of the actions table keep working. The actions table itself has always had to be set up by hand.
+    pub async fn create_tables(&self) -> Result<(), WakeBotDbError> {
+        for table in [ROLLS_TABLE, LUCK_TABLE] {
+            let result = self
+                .client
+                .create_table()
+                .table_name(table)
+                .attribute_definitions(
+                    AttributeDefinition::builder()
+                        .attribute_name("name")
+                        .attribute_type(ScalarAttributeType::S)
+                        .build(),
+                )
+                .key_schema(
+                    KeySchemaElement::builder()
+                        .attribute_name("name")
+                        .key_type(KeyType::Hash)
+                        .build(),
+                )
+                .billing_mode(BillingMode::PayPerRequest)
+                .send()
+                .await;
+            match result {
+                Ok(_) => println!("Created the '{}' table", table),
+                Err(e)
+                    if e.as_service_error()
+                        .is_some_and(|e| e.is_resource_in_use_exception()) => {}
+                Err(e) => return Err(WakeBotDbError::AWSCreateTableError(e)),
+            }
+        }
+        Ok(())
+    }
+
+    // Reads every item in a table matching a filter, following the scan over as many pages as it takes. The
+    // filter can refer to the key as #name, and to the given values by their placeholders, e.g. ":prefix".
+    async fn scan(
+        &self,
+        table: &str,
+        filter: &str,
+        values: Vec<(&str, AttributeValue)>,
+    ) -> Result<Vec<HashMap<String, AttributeValue>>, WakeBotDbError> {
+        let mut items = vec![];
+        let mut start_key = None;
+        loop {
+            let mut request = self
+                .client
+                .scan()
+                .table_name(table)
+                .filter_expression(filter)
+                .expression_attribute_names("#name", "name");
+            for (placeholder, value) in values.iter() {
+                request = request.expression_attribute_values(*placeholder, value.clone());
+            }
+            let output = request
+                .set_exclusive_start_key(start_key)
+                .send()
+                .await
+                .map_err(|e| WakeBotDbError::AWSScanError(e))?;
+            items.extend(output.items().unwrap_or_default().iter().cloned());
+            start_key = output.last_evaluated_key().cloned();
+            if start_key.is_none() {
+                break;
+            }
+        }
+        Ok(items)
+    }
 }
 
-pub async fn delete_action(
-    client: &Client,
-    action_name: &str,
-) -> Result<DeleteItemOutput, SdkError<DeleteItemError>> {
-    client
-        .delete_item()
-        .table_name("actions")
-        .key("name", AttributeValue::S(action_name.into()))
-        .send()
-        .await
+// Works out which action an item holds from its key, or None if it's something else or belongs to another user
+fn action_from_item(
+    item: &HashMap<String, AttributeValue>,
+    guild: &str,
+    user_id: &str,
+) -> Option<Action> {
+    let key = item.get("name")?.as_s().ok()?;
+    let (name, scope) = match key.strip_prefix(&format!("action:{}:", guild)) {
+        Some(rest) => match rest
+            .strip_prefix("user:")
+            .and_then(|rest| rest.split_once(':'))
+        {
+            Some((owner, name)) if owner == user_id => (
+                name,
+                ActionScope::Personal {
+                    guild: String::from(guild),
+                    user_id: String::from(user_id),
+                },
+            ),
+            Some(_) => return None,
+            None => (
+                rest,
+                ActionScope::Server {
+                    guild: String::from(guild),
+                },
+            ),
+        },
+        // The heh counter is stored like a global action, but isn't one
+        None if key == "heh" => return None,
+        None => (key.as_str(), ActionScope::Global),
+    };
+    let get_string = |key: &str| {
+        item.get(key)
+            .and_then(|val| val.as_s().ok())
+            .cloned()
+            .unwrap_or_default()
+    };
+    Some(Action {
+        name: String::from(name),
+        roll: get_string("roll"),
+        scope,
+        owner_id: get_string("owner_id"),
+    })
 }
 
-#[derive(std::fmt::Debug)]
-pub enum WakeBotDbError {
-    AWSGetError(SdkError<GetItemError>),
-    AWSPutError(SdkError<PutItemError>),
-    NotFound(WakeBotError),
+// The attribute holding one of a die size's running totals, e.g. "d20_rolled"
+fn luck_attribute(sides: u32, count: &str) -> String {
+    format!("d{}_{}", sides, count)
 }
 
-pub async fn get_action_roll(client: &Client, action_name: &str) -> Result<String, WakeBotDbError> {
-    let str = client
-        .get_item()
-        .table_name("actions")
-        .key("name", AttributeValue::S(action_name.into()))
-        .send()
-        .await
-        .map_err(|e| WakeBotDbError::AWSGetError(e))?;
-    let str = if let Some(val) = str.item() {
-        val
-    } else {
-        return Err(WakeBotDbError::NotFound(WakeBotError::new(
-            "Action does not exist.",
-        )));
-    };
-    Ok(String::from(str.get("roll").unwrap().as_s().unwrap()))
+fn luck_stats_from_item(item: &HashMap<String, AttributeValue>) -> LuckStats {
+    let mut stats = LuckStats::default();
+    for (key, val) in item {
+        let (sides, count) = match key.strip_prefix('d').and_then(|key| key.split_once('_')) {
+            Some((sides, count)) => (sides, count),
+            None => continue,
+        };
+        let (sides, n) = match (
+            sides.parse::<u32>(),
+            val.as_n().ok().and_then(|n| n.parse::<u64>().ok()),
+        ) {
+            (Ok(sides), Some(n)) => (sides, n),
+            _ => continue,
+        };
+        let die = stats.dice.entry(sides).or_default();
+        match count {
+            "rolled" => die.rolled = n,
+            "total" => die.total = n,
+            "highest" => die.highest = n,
+            "lowest" => die.lowest = n,
+            _ => {}
+        }
+    }
+    stats
 }
 
-pub async fn increment_hehs(client: &Client) -> Result<i32, WakeBotDbError> {
-    let str = client
-        .get_item()
-        .table_name("actions")
-        .key("name", AttributeValue::S(String::from("heh")))
-        .send()
-        .await
-        .map_err(|e| WakeBotDbError::AWSGetError(e))?;
-    let num = if let Some(val) = str.item() {
-        let current_val = val
-            .get("roll")
-            .unwrap()
-            .as_s()
-            .unwrap()
-            .parse::<i32>()
-            .unwrap();
-        current_val + 1
-    } else {
-        1
-    };
-    client
-        .put_item()
-        .table_name("actions")
-        .item("name", AttributeValue::S(String::from("heh")))
-        .item("roll", AttributeValue::S(num.to_string()))
-        .send()
-        .await
-        .map_err(|e| WakeBotDbError::AWSPutError(e))?;
-    Ok(num)
+#[async_trait]
+impl Storage for DynamoDbStorage {
+    async fn get_scoped_action(
+        &self,
+        scope: &ActionScope,
+        action_name: &str,
+    ) -> Result<Action, WakeBotDbError> {
+        let str = self
+            .client
+            .get_item()
+            .table_name(ACTIONS_TABLE)
+            .key("name", AttributeValue::S(scope.key(action_name)))
+            .send()
+            .await
+            .map_err(|e| WakeBotDbError::AWSGetError(e))?;
+        // Anything without a roll isn't an action, and neither is the heh counter
+        let item = match str.item() {
+            Some(item) if !(*scope == ActionScope::Global && action_name == "heh") => item,
+            _ => return Err(action_not_found()),
+        };
+        let roll = match item.get("roll").and_then(|val| val.as_s().ok()) {
+            Some(roll) => roll,
+            None => return Err(action_not_found()),
+        };
+        let owner_id = item
+            .get("owner_id")
+            .and_then(|val| val.as_s().ok())
+            .cloned()
+            .unwrap_or_default();
+        Ok(Action {
+            name: String::from(action_name),
+            roll: roll.clone(),
+            scope: scope.clone(),
+            owner_id,
+        })
+    }
+
+    async fn add_or_update_action(&self, action: &Action) -> Result<(), WakeBotDbError> {
+        let name_av = AttributeValue::S(action.scope.key(&action.name));
+        let roll_av = AttributeValue::S(action.roll.clone());
+        self.client
+            .put_item()
+            .table_name(ACTIONS_TABLE)
+            .item("name", name_av)
+            .item("roll", roll_av)
+            .item("owner_id", AttributeValue::S(action.owner_id.clone()))
+            .send()
+            .await
+            .map_err(|e| WakeBotDbError::AWSPutError(e))?;
+        Ok(())
+    }
+
+    async fn delete_action(
+        &self,
+        scope: &ActionScope,
+        action_name: &str,
+    ) -> Result<(), WakeBotDbError> {
+        self.client
+            .delete_item()
+            .table_name(ACTIONS_TABLE)
+            .key("name", AttributeValue::S(scope.key(action_name)))
+            .send()
+            .await
+            .map_err(|e| WakeBotDbError::AWSDeleteError(e))?;
+        Ok(())
+    }
+
+    // Global actions are the only items without a ':' in their key
+    async fn list_actions(
+        &self,
+        guild: &str,
+        user_id: &str,
+    ) -> Result<Vec<Action>, WakeBotDbError> {
+        let items = self
+            .scan(
+                ACTIONS_TABLE,
+                "begins_with(#name, :prefix) OR NOT contains(#name, :separator)",
+                vec![
+                    (":prefix", AttributeValue::S(format!("action:{}:", guild))),
+                    (":separator", AttributeValue::S(String::from(":"))),
+                ],
+            )
+            .await?;
+        Ok(items
+            .iter()
+            .filter_map(|item| action_from_item(item, guild, user_id))
+            .collect())
+    }
+
+    // Counters are stored like an action, with the count as the roll
+    async fn increment_counter(&self, name: &str) -> Result<i32, WakeBotDbError> {
+        let str = self
+            .client
+            .get_item()
+            .table_name(ACTIONS_TABLE)
+            .key("name", AttributeValue::S(String::from(name)))
+            .send()
+            .await
+            .map_err(|e| WakeBotDbError::AWSGetError(e))?;
+        let num = if let Some(val) = str.item() {
+            let current_val = val
+                .get("roll")
+                .unwrap()
+                .as_s()
+                .unwrap()
+                .parse::<i32>()
+                .unwrap();
+            current_val + 1
+        } else {
+            1
+        };
+        self.client
+            .put_item()
+            .table_name(ACTIONS_TABLE)
+            .item("name", AttributeValue::S(String::from(name)))
+            .item("roll", AttributeValue::S(num.to_string()))
+            .send()
+            .await
+            .map_err(|e| WakeBotDbError::AWSPutError(e))?;
+        Ok(num)
+    }
+
+    async fn get_crit_profile(&self, scope: &str) -> Result<Option<String>, WakeBotDbError> {
+        let item = self
+            .client
+            .get_item()
+            .table_name(ACTIONS_TABLE)
+            .key("name", AttributeValue::S(format!("crit:{}", scope)))
+            .send()
+            .await
+            .map_err(|e| WakeBotDbError::AWSGetError(e))?;
+        Ok(item
+            .item()
+            .and_then(|val| val.get("roll"))
+            .and_then(|roll| roll.as_s().ok())
+            .map(String::from))
+    }
+
+    async fn set_crit_profile(&self, scope: &str, profile: &str) -> Result<(), WakeBotDbError> {
+        self.client
+            .put_item()
+            .table_name(ACTIONS_TABLE)
+            .item("name", AttributeValue::S(format!("crit:{}", scope)))
+            .item("roll", AttributeValue::S(String::from(profile)))
+            .send()
+            .await
+            .map_err(|e| WakeBotDbError::AWSPutError(e))?;
+        Ok(())
+    }
+
+    async fn add_roll_record(&self, record: &RollRecord) -> Result<(), WakeBotDbError> {
+        let result = self
+            .client
+            .put_item()
+            .table_name(ROLLS_TABLE)
+            .item("name", AttributeValue::S(record.id.clone()))
+            .item("expression", AttributeValue::S(record.expression.clone()))
+            .item("user_id", AttributeValue::S(record.user_id.clone()))
+            .item("user_name", AttributeValue::S(record.user_name.clone()))
+            .item("channel_id", AttributeValue::S(record.channel_id.clone()))
+            .item("timestamp", AttributeValue::N(record.timestamp.to_string()))
+            .item("result", AttributeValue::S(record.result.clone()))
+            .item("seed", AttributeValue::N(record.seed.to_string()))
+            .item("is_private", AttributeValue::Bool(record.is_private))
+            .condition_expression("attribute_not_exists(#name)")
+            .expression_attribute_names("#name", "name")
+            .send()
+            .await;
+        match result {
+            Ok(_) => Ok(()),
+            Err(e)
+                if e.as_service_error()
+                    .map_or(false, |e| e.is_conditional_check_failed_exception()) =>
+            {
+                Err(WakeBotDbError::AlreadyExists)
+            }
+            Err(e) => Err(WakeBotDbError::AWSPutError(e)),
+        }
+    }
+
+    async fn get_roll_record(&self, id: &str) -> Result<RollRecord, WakeBotDbError> {
+        let item = self
+            .client
+            .get_item()
+            .table_name(ROLLS_TABLE)
+            .key("name", AttributeValue::S(String::from(id)))
+            .send()
+            .await
+            .map_err(|e| WakeBotDbError::AWSGetError(e))?;
+        let item = if let Some(val) = item.item() {
+            val
+        } else {
+            return Err(roll_not_found());
+        };
+        let get_string = |key: &str| {
+            item.get(key)
+                .and_then(|val| val.as_s().ok())
+                .cloned()
+                .unwrap_or_default()
+        };
+        let get_number = |key: &str| {
+            item.get(key)
+                .and_then(|val| val.as_n().ok())
+                .cloned()
+                .unwrap_or_default()
+        };
+        Ok(RollRecord {
+            id: String::from(id),
+            expression: get_string("expression"),
+            user_id: get_string("user_id"),
+            user_name: get_string("user_name"),
+            channel_id: get_string("channel_id"),
+            timestamp: get_number("timestamp").parse().unwrap_or_default(),
+            result: get_string("result"),
+            seed: get_number("seed").parse().unwrap_or_default(),
+            is_private: item
+                .get("is_private")
+                .and_then(|val| val.as_bool().ok())
+                .copied()
+                .unwrap_or_default(),
+        })
+    }
+
+    // Variables are stored like crit profiles, with the value as the roll
+    async fn get_variable(
+        &self,
+        guild: &str,
+        user_id: &str,
+        name: &str,
+    ) -> Result<Option<String>, WakeBotDbError> {
+        let item = self
+            .client
+            .get_item()
+            .table_name(ACTIONS_TABLE)
+            .key(
+                "name",
+                AttributeValue::S(format!("var:{}:{}:{}", guild, user_id, name)),
+            )
+            .send()
+            .await
+            .map_err(|e| WakeBotDbError::AWSGetError(e))?;
+        Ok(item
+            .item()
+            .and_then(|val| val.get("roll"))
+            .and_then(|roll| roll.as_s().ok())
+            .map(String::from))
+    }
+
+    async fn set_variable(
+        &self,
+        guild: &str,
+        user_id: &str,
+        name: &str,
+        value: &str,
+    ) -> Result<(), WakeBotDbError> {
+        self.client
+            .put_item()
+            .table_name(ACTIONS_TABLE)
+            .item(
+                "name",
+                AttributeValue::S(format!("var:{}:{}:{}", guild, user_id, name)),
+            )
+            .item("roll", AttributeValue::S(String::from(value)))
+            .send()
+            .await
+            .map_err(|e| WakeBotDbError::AWSPutError(e))?;
+        Ok(())
+    }
+
+    async fn delete_variable(
+        &self,
+        guild: &str,
+        user_id: &str,
+        name: &str,
+    ) -> Result<(), WakeBotDbError> {
+        self.client
+            .delete_item()
+            .table_name(ACTIONS_TABLE)
+            .key(
+                "name",
+                AttributeValue::S(format!("var:{}:{}:{}", guild, user_id, name)),
+            )
+            .send()
+            .await
+            .map_err(|e| WakeBotDbError::AWSDeleteError(e))?;
+        Ok(())
+    }
+
+    async fn list_variables(
+        &self,
+        guild: &str,
+        user_id: &str,
+    ) -> Result<Vec<(String, String)>, WakeBotDbError> {
+        let prefix = format!("var:{}:{}:", guild, user_id);
+        let items = self
+            .scan(
+                ACTIONS_TABLE,
+                "begins_with(#name, :prefix)",
+                vec![(":prefix", AttributeValue::S(prefix.clone()))],
+            )
+            .await?;
+        let mut variables = items
+            .iter()
+            .filter_map(|item| {
+                let name = item.get("name")?.as_s().ok()?.strip_prefix(&prefix)?;
+                let value = item.get("roll")?.as_s().ok()?;
+                Some((String::from(name), value.clone()))
+            })
+            .collect::<Vec<(String, String)>>();
+        variables.sort();
+        Ok(variables)
+    }
+
+    async fn get_luck_stats(&self, scope: &str) -> Result<LuckStats, WakeBotDbError> {
+        let item = self
+            .client
+            .get_item()
+            .table_name(LUCK_TABLE)
+            .key("name", AttributeValue::S(String::from(scope)))
+            .send()
+            .await
+            .map_err(|e| WakeBotDbError::AWSGetError(e))?;
+        Ok(item.item().map(luck_stats_from_item).unwrap_or_default())
+    }
+
+    // ADD creates any total that doesn't exist yet, starting it from zero
+    async fn add_luck_stats(
+        &self,
+        scope: &str,
+        user_name: &str,
+        stats: &LuckStats,
+    ) -> Result<(), WakeBotDbError> {
+        if stats.is_empty() {
+            return Ok(());
+        }
+        let mut request = self
+            .client
+            .update_item()
+            .table_name(LUCK_TABLE)
+            .key("name", AttributeValue::S(String::from(scope)))
+            .expression_attribute_names("#user_name", "user_name")
+            .expression_attribute_values(":user_name", AttributeValue::S(String::from(user_name)));
+        let mut additions = vec![];
+        for (sides, die) in stats.dice.iter() {
+            for (count, n) in [
+                ("rolled", die.rolled),
+                ("total", die.total),
+                ("highest", die.highest),
+                ("lowest", die.lowest),
+            ] {
+                let attribute = luck_attribute(*sides, count);
+                additions.push(format!("#{} :{}", attribute, attribute));
+                request = request
+                    .expression_attribute_names(format!("#{}", attribute), attribute.clone())
+                    .expression_attribute_values(
+                        format!(":{}", attribute),
+                        AttributeValue::N(n.to_string()),
+                    );
+            }
+        }
+        request
+            .update_expression(format!(
+                "SET #user_name = :user_name ADD {}",
+                additions.join(", ")
+            ))
+            .send()
+            .await
+            .map_err(|e| WakeBotDbError::AWSUpdateError(e))?;
+        Ok(())
+    }
+
+    async fn get_all_luck_stats(
+        &self,
+        prefix: &str,
+    ) -> Result<Vec<(String, LuckStats)>, WakeBotDbError> {
+        let items = self
+            .scan(
+                LUCK_TABLE,
+                "begins_with(#name, :prefix)",
+                vec![(":prefix", AttributeValue::S(String::from(prefix)))],
+            )
+            .await?;
+        Ok(items
+            .iter()
+            .map(|item| {
+                let user_name = item
+                    .get("user_name")
+                    .and_then(|val| val.as_s().ok())
+                    .cloned()
+                    .unwrap_or_default();
+                (user_name, luck_stats_from_item(item))
+            })
+            .collect())
+    }
 }
@@ -0,0 +1,218 @@
+use crate::errors::{format_error, WakeBotError};
+use crate::rolls::{
+    format_roll_detail, format_roll_line, format_tag_totals, split_repeated_label, RollResult,
+    RollStringResult,
+};
+use serenity::builder::CreateEmbed;
+use serenity::utils::Colour;
+
+// Discord's limits for a single embed
+const MAX_TITLE_LENGTH: usize = 256;
+const MAX_FIELDS: usize = 25;
+const MAX_FIELD_LENGTH: usize = 1024;
+
+fn truncate(text: &str, max_length: usize) -> String {
+    if text.chars().count() <= max_length {
+        return String::from(text);
+    }
+    text.chars().take(max_length - 1).collect::<String>() + "…"
+}
+
+// An embed along with how many characters Discord counts towards its limit for a whole message, which
+// covers the title, description, footer and every field
+#[derive(Default)]
+pub struct RollEmbed {
+    pub embed: CreateEmbed,
+    pub length: usize,
+}
+
+impl RollEmbed {
+    fn title(&mut self, title: String) {
+        self.length += title.chars().count();
+        self.embed.title(title);
+    }
+
+    fn description(&mut self, description: String) {
+        self.length += description.chars().count();
+        self.embed.description(description);
+    }
+
+    fn field(&mut self, name: String, value: String, inline: bool) {
+        self.length += name.chars().count() + value.chars().count();
+        self.embed.field(name, value, inline);
+    }
+
+    fn footer(&mut self, text: String) {
+        self.length += text.chars().count();
+        self.embed.footer(|f| f.text(text));
+    }
+}
+
+fn title(expression: &str, label: Option<&str>) -> String {
+    let title = match label {
+        Some(label) => format!("{} ({})", label, expression),
+        None => String::from(expression),
+    };
+    truncate(&title, MAX_TITLE_LENGTH)
+}
+
+// Green for a crit success, red for a crit failure or botch, and gold when a roll somehow has both
+fn crit_colour<'a>(rolls: impl Iterator<Item = &'a RollResult>) -> Option<Colour> {
+    let (mut success, mut failure) = (false, false);
+    for roll in rolls {
+        success |= roll.has_critical_success;
+        failure |= roll.has_critical_failure || roll.has_botch;
+    }
+    match (success, failure) {
+        (true, true) => Some(Colour::GOLD),
+        (true, false) => Some(Colour::DARK_GREEN),
+        (false, true) => Some(Colour::RED),
+        (false, false) => None,
+    }
+}
+
+// Gives nothing when there are too many rolls to show one per field
+pub fn roll_embed(result: &RollStringResult) -> Option<RollEmbed> {
+    if result.rolls.len() > MAX_FIELDS {
+        return None;
+    }
+    let mut embed = RollEmbed::default();
+    embed.title(title(result.original_text, result.label.as_deref()));
+    for roll in result.rolls.iter() {
+        embed.field(
+            truncate(&roll.original_text, MAX_TITLE_LENGTH),
+            truncate(&format_roll_detail(roll), MAX_FIELD_LENGTH),
+            false,
+        );
+    }
+    let mut description = format_tag_totals(&result.rolls);
+    if result.rolls.len() > 1 {
+        description = result.converted_text.replace("*", r"\*") + "\n" + &description;
+    }
+    if !description.is_empty() {
+        embed.description(description);
+    }
+    embed.footer(format!("Total: {}", result.total));
+    if let Some(colour) = crit_colour(result.rolls.iter()) {
+        embed.embed.colour(colour);
+    }
+    Some(embed)
+}
+
+pub fn repeated_roll_embed(
+    original_text: &str,
+    results: &[RollStringResult],
+    show_sum: bool,
+    show_sorted: bool,
+) -> Option<RollEmbed> {
+    if results.len() > MAX_FIELDS {
+        return None;
+    }
+    let (original_text, label) = split_repeated_label(original_text);
+    let mut embed = RollEmbed::default();
+    embed.title(title(original_text, label));
+    for (i, result) in results.iter().enumerate() {
+        embed.field(
+            format!("#{}", i + 1),
+            truncate(&format_roll_line(result), MAX_FIELD_LENGTH),
+            true,
+        );
+    }
+    let totals = results
+        .iter()
+        .map(|result| result.total)
+        .collect::<Vec<f64>>();
+    let mut footer = vec![];
+    if show_sum {
+        footer.push(format!("Sum: {}", totals.iter().sum::<f64>()));
+    }
+    if show_sorted {
+        let mut sorted = totals.clone();
+        sorted.sort_by(|a, b| b.total_cmp(a));
+        footer.push(format!(
+            "Sorted: {}",
+            sorted
+                .iter()
+                .map(|total| total.to_string())
+                .collect::<Vec<String>>()
+                .join(", ")
+        ));
+    }
+    if !footer.is_empty() {
+        embed.footer(footer.join(" | "));
+    }
+    if let Some(colour) = crit_colour(results.iter().flat_map(|result| result.rolls.iter())) {
+        embed.embed.colour(colour);
+    }
+    Some(embed)
+}
+
+pub fn error_embed(roll_str: &str, error: &WakeBotError) -> RollEmbed {
+    let mut embed = RollEmbed::default();
+    embed.title(title(roll_str, None));
+    embed.description(format_error(roll_str, error));
+    embed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rolls::interpret_rolls;
+    use crate::rolls::tests::fixed;
+
+    fn text<'a>(embed: &'a RollEmbed, key: &str) -> &'a str {
+        embed.embed.0[key].as_str().unwrap()
+    }
+
+    fn colour(embed: &RollEmbed) -> Option<u64> {
+        embed
+            .embed
+            .0
+            .get("color")
+            .and_then(|colour| colour.as_u64())
+    }
+
+    fn roll_colour(roll: &str, values: &[i32]) -> Option<u64> {
+        colour(&roll_embed(&interpret_rolls(roll, &mut fixed(values)).unwrap()).unwrap())
+    }
+
+    #[test]
+    fn truncates_to_discord_limits() {
+        assert_eq!(truncate("1d20", 4), "1d20");
+        assert_eq!(truncate("1d20+5", 4), "1d2…");
+        assert_eq!(truncate(&"é".repeat(300), 256).chars().count(), 256);
+        let roll = format!("1d20+{}1 # {}", "1+".repeat(600), "a".repeat(300));
+        let embed = roll_embed(&interpret_rolls(&roll, &mut fixed(&[10])).unwrap()).unwrap();
+        let title = text(&embed, "title");
+        assert_eq!(title.chars().count(), MAX_TITLE_LENGTH);
+        assert!(title.ends_with('…'));
+        let field = embed.embed.0["fields"][0]["value"].as_str().unwrap();
+        assert_eq!(field.chars().count(), MAX_FIELD_LENGTH);
+        assert!(field.ends_with('…'));
+    }
+
+    #[test]
+    fn colours_crits_and_fumbles() {
+        assert_eq!(
+            roll_colour("1d20", &[20]),
+            Some(u64::from(Colour::DARK_GREEN.0))
+        );
+        assert_eq!(roll_colour("1d20", &[1]), Some(u64::from(Colour::RED.0)));
+        assert_eq!(
+            roll_colour("1d20+1d20", &[20, 1]),
+            Some(u64::from(Colour::GOLD.0))
+        );
+        assert_eq!(roll_colour("1d20", &[10]), None);
+        // Botches count as failures
+        assert_eq!(
+            roll_colour("3d10>=7", &[1, 2, 3]),
+            Some(u64::from(Colour::RED.0))
+        );
+        let results = [
+            interpret_rolls("1d20", &mut fixed(&[10])).unwrap(),
+            interpret_rolls("1d20", &mut fixed(&[20])).unwrap(),
+        ];
+        let embed = repeated_roll_embed("2x 1d20", &results, false, false).unwrap();
+        assert_eq!(colour(&embed), Some(u64::from(Colour::DARK_GREEN.0)));
+    }
+}
@@ -1,32 +1,213 @@
 use std::error::Error;
 use std::fmt;
+use std::ops::Range;
 
-pub struct WakeBotError {
-    details: String,
+// What went wrong with part of a roll expression
+#[derive(Debug, Clone, PartialEq)]
+pub enum RollErrorKind {
+    UnexpectedCharacter(char),
+    UnexpectedToken(String),
+    UnexpectedEnd,
+    // Names what was left open, e.g. "parenthesis"
+    Unclosed(&'static str),
+    // Describes what should have come next, e.g. "a die size after 'd'"
+    Expected(String),
+    InvalidNumber(String),
+    NumberTooLarge(String),
+    InvalidDieSize(u32),
+    FaceTooLarge(u32),
+    // The maximum number of dice
+    TooManyDice(usize),
+    TooManyDiceRolled(usize),
+    KeepTooMany { keep: usize, count: usize },
+    DropTooMany { drop: usize, count: usize },
+    AlwaysRerolls,
+    AlwaysExplodes,
+    PoolWithoutSuccess,
+    DivideByZero,
+    InvalidTarget(String),
+    // The maximum number of actions and variables looked up for one roll
+    TooManyReferences(usize),
+    // The maximum length of a roll once its references are expanded
+    ExpandedTooLong(usize),
+}
+
+impl fmt::Display for RollErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RollErrorKind::UnexpectedCharacter(c) => {
+                write!(f, "Unexpected character '{}' in roll", c)
+            }
+            RollErrorKind::UnexpectedToken(text) => write!(f, "Unexpected '{}' in roll", text),
+            RollErrorKind::UnexpectedEnd => write!(f, "Unexpected end of roll"),
+            RollErrorKind::Unclosed(what) => write!(f, "Missing closing {}", what),
+            RollErrorKind::Expected(what) => write!(f, "Expected {}", what),
+            RollErrorKind::InvalidNumber(text) => write!(f, "Invalid number '{}'", text),
+            RollErrorKind::NumberTooLarge(text) => write!(f, "Number '{}' is too large", text),
+            RollErrorKind::InvalidDieSize(sides) => write!(f, "Invalid die size d{}", sides),
+            RollErrorKind::FaceTooLarge(value) => write!(f, "Face {} is too large", value),
+            RollErrorKind::TooManyDice(max) => write!(f, "Max number of dice is {}", max),
+            RollErrorKind::TooManyDiceRolled(max) => write!(
+                f,
+                "Max number of dice is {}, including explosions and rerolls",
+                max
+            ),
+            RollErrorKind::KeepTooMany { keep, count } => {
+                write!(f, "Cannot keep {} dice out of {}", keep, count)
+            }
+            RollErrorKind::DropTooMany { drop, count } => {
+                write!(f, "Cannot drop {} dice out of {}", drop, count)
+            }
+            RollErrorKind::AlwaysRerolls => {
+                write!(f, "Dice cannot be rerolled on every possible roll")
+            }
+            RollErrorKind::AlwaysExplodes => {
+                write!(f, "Dice cannot explode on every possible roll")
+            }
+            RollErrorKind::PoolWithoutSuccess => write!(
+                f,
+                "Failures and double successes need a success target first, e.g. 8d10>=7f1"
+            ),
+            RollErrorKind::DivideByZero => write!(f, "Cannot divide by zero"),
+            RollErrorKind::InvalidTarget(text) => write!(f, "Invalid target '{}'", text),
+            RollErrorKind::TooManyReferences(max) => {
+                write!(f, "Max number of references looked up is {}", max)
+            }
+            RollErrorKind::ExpandedTooLong(max) => write!(
+                f,
+                "Roll is longer than {} characters once references are expanded",
+                max
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum WakeBotError {
+    // A problem with part of a roll expression. The span is in bytes, relative to the text being rolled.
+    Roll {
+        kind: RollErrorKind,
+        span: Range<usize>,
+    },
+    EmptyRoll,
+    // The maximum number of repeats
+    InvalidRepeatCount(usize),
+    MissingTarget,
+    // Problems with the arguments given to an action, each naming the parameter involved
+    MissingArgument(String),
+    UnknownArgument(String),
+    InvalidArgument {
+        name: String,
+        error: Box<WakeBotError>,
+    },
+    // The number of positional parameters the action has
+    TooManyArguments(usize),
+    // Problems with the actions and variables a roll refers to, each named as written, e.g. "@str_mod"
+    UnknownReference(String),
+    InvalidReference {
+        name: String,
+        error: Box<WakeBotError>,
+    },
+    // Every reference followed, ending with the one that led back to an earlier one
+    ReferenceCycle(Vec<String>),
+    // The maximum depth
+    ReferencesTooDeep(usize),
+    Message(String),
 }
 
 impl WakeBotError {
     pub fn new(msg: &str) -> WakeBotError {
-        WakeBotError {
-            details: msg.to_string(),
+        WakeBotError::Message(msg.to_string())
+    }
+
+    pub fn roll(kind: RollErrorKind, span: Range<usize>) -> WakeBotError {
+        WakeBotError::Roll { kind, span }
+    }
+
+    pub fn span(&self) -> Option<Range<usize>> {
+        match self {
+            WakeBotError::Roll { span, .. } => Some(span.clone()),
+            _ => None,
         }
     }
-}
 
-impl fmt::Display for WakeBotError {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", self.details)
+    // Moves the span along by a number of bytes, for when the text that was parsed started that far into a
+    // longer string
+    pub fn offset_by(self, offset: usize) -> WakeBotError {
+        match self {
+            WakeBotError::Roll { kind, span } => WakeBotError::Roll {
+                kind,
+                span: span.start + offset..span.end + offset,
+            },
+            other => other,
+        }
     }
 }
 
-impl fmt::Debug for WakeBotError {
+impl fmt::Display for WakeBotError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", self.details)
+        match self {
+            WakeBotError::Roll { kind, .. } => write!(f, "{}", kind),
+            WakeBotError::EmptyRoll => write!(f, "Empty roll"),
+            WakeBotError::InvalidRepeatCount(max) => {
+                write!(f, "Rolls can be repeated between 1 and {} times", max)
+            }
+            WakeBotError::MissingTarget => {
+                write!(f, "Expected a target after the roll, e.g. '1d20+5 >= 15'")
+            }
+            WakeBotError::MissingArgument(name) => write!(f, "No value given for '{{{}}}'", name),
+            WakeBotError::UnknownArgument(name) => {
+                write!(f, "The action has no parameter named '{}'", name)
+            }
+            WakeBotError::InvalidArgument { name, error } => {
+                write!(
+                    f,
+                    "The value for '{{{}}}' isn't a valid roll: {}",
+                    name, error
+                )
+            }
+            WakeBotError::TooManyArguments(count) => write!(
+                f,
+                "Too many arguments, the action only takes {} without a name",
+                count
+            ),
+            WakeBotError::UnknownReference(name) => {
+                write!(f, "There's no action or variable called '{}'", name)
+            }
+            WakeBotError::InvalidReference { name, error } => {
+                write!(f, "Couldn't use '{}': {}", name, error)
+            }
+            WakeBotError::ReferenceCycle(chain) => write!(
+                f,
+                "'{}' ends up referring to itself: {}",
+                chain.last().map(String::as_str).unwrap_or_default(),
+                chain.join(" -> ")
+            ),
+            WakeBotError::ReferencesTooDeep(max) => {
+                write!(
+                    f,
+                    "Actions and variables can only refer {} levels deep",
+                    max
+                )
+            }
+            WakeBotError::Message(msg) => write!(f, "{}", msg),
+        }
     }
 }
 
-impl Error for WakeBotError {
-    fn description(&self) -> &str {
-        &self.details
+impl Error for WakeBotError {}
+
+// Shows an error beneath the text it came from, with carets under the part at fault:
+// 1d20+*5
+//      ^
+pub fn format_error(source: &str, error: &WakeBotError) -> String {
+    let underline = error.span().and_then(|span| {
+        let before = source.get(..span.start)?;
+        let marked = source.get(span)?;
+        Some(" ".repeat(before.chars().count()) + &"^".repeat(marked.chars().count().max(1)))
+    });
+    match underline {
+        Some(underline) => format!("```\n{}\n{}\n```\nErr: {}", source, underline, error),
+        None => format!("{}\nErr: {}", source, error),
     }
 }
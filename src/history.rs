@@ -0,0 +1,156 @@
+use crate::rolls::RollOptions;
+use serenity::model::id::{ChannelId, UserId};
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+
+// How many rolls are remembered for each user in each channel
+const MAX_HISTORY: usize = 10;
+
+// Everything needed to make a roll again
+#[derive(Debug, Clone)]
+pub struct RollRequest {
+    // How the roll is shown in history, e.g. the expression or the action it came from
+    pub name: String,
+    pub roll: String,
+    pub options: RollOptions,
+    pub show_sum: bool,
+    pub show_sorted: bool,
+    pub is_private: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct HistoryEntry {
+    pub request: RollRequest,
+    pub totals: Vec<f64>,
+    pub roll_id: Option<String>,
+}
+
+// Recent rolls are only kept in memory, so they are forgotten when the bot restarts
+#[derive(Default)]
+pub struct RollHistory {
+    entries: Mutex<HashMap<(UserId, ChannelId), VecDeque<HistoryEntry>>>,
+}
+
+impl RollHistory {
+    pub fn push(&self, user: UserId, channel: ChannelId, entry: HistoryEntry) {
+        let mut entries = self.entries.lock().unwrap();
+        let history = entries.entry((user, channel)).or_default();
+        history.push_front(entry);
+        history.truncate(MAX_HISTORY);
+    }
+
+    pub fn last(&self, user: UserId, channel: ChannelId) -> Option<HistoryEntry> {
+        self.entries
+            .lock()
+            .unwrap()
+            .get(&(user, channel))
+            .and_then(|history| history.front().cloned())
+    }
+
+    // Newest first
+    pub fn recent(&self, user: UserId, channel: ChannelId) -> Vec<HistoryEntry> {
+        self.entries
+            .lock()
+            .unwrap()
+            .get(&(user, channel))
+            .map(|history| history.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+}
+
+pub fn format_history_entry(entry: &HistoryEntry) -> String {
+    // Private results stay private, even in history
+    let totals = if entry.request.is_private {
+        String::from("(private)")
+    } else if entry.totals.is_empty() {
+        String::from("(error)")
+    } else {
+        String::from("= ")
+            + &entry
+                .totals
+                .iter()
+                .map(|total| format!("**{}**", total))
+                .collect::<Vec<String>>()
+                .join(", ")
+    };
+    let roll_id = entry
+        .roll_id
+        .as_ref()
+        .map(|id| format!(" [`{}`]", id))
+        .unwrap_or_default();
+    format!("`{}` {}{}", entry.request.name, totals, roll_id)
+}
+
+pub fn format_history(entries: &[HistoryEntry]) -> String {
+    entries
+        .iter()
+        .enumerate()
+        .map(|(i, entry)| format!("{}. {}", i + 1, format_history_entry(entry)))
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(name: &str, totals: &[f64], is_private: bool) -> HistoryEntry {
+        HistoryEntry {
+            request: RollRequest {
+                name: String::from(name),
+                roll: String::from(name),
+                options: RollOptions::default(),
+                show_sum: false,
+                show_sorted: false,
+                is_private,
+            },
+            totals: totals.to_vec(),
+            roll_id: None,
+        }
+    }
+
+    fn names(entries: &[HistoryEntry]) -> Vec<String> {
+        entries
+            .iter()
+            .map(|entry| entry.request.name.clone())
+            .collect()
+    }
+
+    #[test]
+    fn keeps_the_newest_rolls_first() {
+        let history = RollHistory::default();
+        let (user, channel) = (UserId(1), ChannelId(2));
+        for i in 0..MAX_HISTORY + 2 {
+            history.push(user, channel, entry(&format!("{}d6", i), &[1.0], false));
+        }
+        let recent = history.recent(user, channel);
+        assert_eq!(recent.len(), MAX_HISTORY);
+        assert_eq!(recent[0].request.name, "11d6");
+        assert_eq!(recent[MAX_HISTORY - 1].request.name, "2d6");
+        assert_eq!(history.last(user, channel).unwrap().request.name, "11d6");
+        // Each user has their own history in each channel
+        history.push(UserId(3), channel, entry("1d20", &[1.0], false));
+        history.push(user, ChannelId(4), entry("1d4", &[1.0], false));
+        assert_eq!(names(&history.recent(UserId(3), channel)), ["1d20"]);
+        assert_eq!(names(&history.recent(user, ChannelId(4))), ["1d4"]);
+        assert!(history.recent(UserId(3), ChannelId(4)).is_empty());
+        assert!(history.last(UserId(3), ChannelId(4)).is_none());
+    }
+
+    #[test]
+    fn hides_private_totals() {
+        let mut secret = entry("1d20+5", &[17.0], true);
+        secret.roll_id = Some(String::from("abc"));
+        let entries = [
+            secret,
+            entry("4d6kh3", &[12.0, 9.0], false),
+            entry("1d6+*2", &[], false),
+        ];
+        assert_eq!(
+            format_history(&entries),
+            "1. `1d20+5` (private) [`abc`]\n\
+             2. `4d6kh3` = **12**, **9**\n\
+             3. `1d6+*2` (error)"
+        );
+    }
+}
@@ -0,0 +1,221 @@
+use crate::rolls::{Faces, RollStringResult};
+use std::collections::BTreeMap;
+
+// Players need a few d20s behind them before their average means anything
+const MIN_LEADERBOARD_D20S: u64 = 10;
+
+// Running totals for every die of one size a user has rolled
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DieStats {
+    pub rolled: u64,
+    pub total: u64,
+    // Times the die landed on its highest face
+    pub highest: u64,
+    // Times the die landed on a 1
+    pub lowest: u64,
+}
+
+impl DieStats {
+    pub fn average(&self) -> f64 {
+        if self.rolled == 0 {
+            0.0
+        } else {
+            self.total as f64 / self.rolled as f64
+        }
+    }
+}
+
+// Keyed by number of sides. Only numbered dice are tracked, so Fate and custom dice are left out.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LuckStats {
+    pub dice: BTreeMap<u32, DieStats>,
+}
+
+impl LuckStats {
+    // Every die counts, including ones that were dropped or rerolled, since they were still rolled
+    pub fn add_rolls(&mut self, results: &[RollStringResult]) {
+        for roll in results.iter().flat_map(|result| result.rolls.iter()) {
+            let sides = match roll.term.faces {
+                Faces::Numbered(sides) => sides,
+                _ => continue,
+            };
+            let stats = self.dice.entry(sides).or_default();
+            for face in roll.rolls.iter().map(|face| face.unsigned_abs()) {
+                stats.rolled += 1;
+                stats.total += face as u64;
+                if face == sides {
+                    stats.highest += 1;
+                }
+                if face == 1 {
+                    stats.lowest += 1;
+                }
+            }
+        }
+    }
+
+    pub fn merge(&mut self, other: &LuckStats) {
+        for (sides, other) in other.dice.iter() {
+            let stats = self.dice.entry(*sides).or_default();
+            stats.rolled += other.rolled;
+            stats.total += other.total;
+            stats.highest += other.highest;
+            stats.lowest += other.lowest;
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.dice.is_empty()
+    }
+}
+
+fn expected_average(sides: u32) -> f64 {
+    (sides as f64 + 1.0) / 2.0
+}
+
+pub fn format_luck(name: &str, stats: &LuckStats) -> String {
+    if stats.is_empty() {
+        return format!("{} hasn't rolled any dice yet.", name);
+    }
+    let mut result = format!("**Dice luck for {}**\n", name);
+    if let Some(d20) = stats.dice.get(&20) {
+        result += &format!(
+            "Average d20: **{:.2}**, Nat 20s: **{}**, Nat 1s: **{}**\n",
+            d20.average(),
+            d20.highest,
+            d20.lowest
+        );
+    }
+    result += &stats
+        .dice
+        .iter()
+        .map(|(sides, die)| {
+            format!(
+                "d{}: {} rolled, average {:.2} (expected {}), {} max, {} ones",
+                sides,
+                die.rolled,
+                die.average(),
+                expected_average(*sides),
+                die.highest,
+                die.lowest
+            )
+        })
+        .collect::<Vec<String>>()
+        .join("\n");
+    result
+}
+
+// Ranks users by their average d20, luckiest first
+pub fn format_leaderboard(users: &[(String, LuckStats)]) -> String {
+    let mut ranked = users
+        .iter()
+        .filter_map(|(name, stats)| {
+            stats
+                .dice
+                .get(&20)
+                .filter(|d20| d20.rolled >= MIN_LEADERBOARD_D20S)
+                .map(|d20| (name, d20))
+        })
+        .collect::<Vec<(&String, &DieStats)>>();
+    if ranked.is_empty() {
+        return format!(
+            "Nobody has rolled {} d20s yet, so there's no leaderboard.",
+            MIN_LEADERBOARD_D20S
+        );
+    }
+    ranked.sort_by(|a, b| b.1.average().total_cmp(&a.1.average()));
+    let lines = ranked
+        .iter()
+        .enumerate()
+        .map(|(i, (name, d20))| {
+            format!(
+                "{}. {}: average **{:.2}** over {} d20s, {} nat 20s, {} nat 1s",
+                i + 1,
+                name,
+                d20.average(),
+                d20.rolled,
+                d20.highest,
+                d20.lowest
+            )
+        })
+        .collect::<Vec<String>>()
+        .join("\n");
+    String::from("**Luck leaderboard**\n") + &lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rolls::interpret_rolls;
+    use crate::rolls::tests::fixed;
+
+    fn d20s(rolled: u64, total: u64, highest: u64, lowest: u64) -> LuckStats {
+        LuckStats {
+            dice: BTreeMap::from([(
+                20,
+                DieStats {
+                    rolled,
+                    total,
+                    highest,
+                    lowest,
+                },
+            )]),
+        }
+    }
+
+    #[test]
+    fn counts_dropped_and_rerolled_dice() {
+        let mut rng = fixed(&[1, 6, 3, 6, 1, 20, 3, 2]);
+        let result = interpret_rolls("4d6kh3+1d20r1+1dF+1d{2,4}", &mut rng).unwrap();
+        let mut stats = LuckStats::default();
+        stats.add_rolls(&[result]);
+        assert_eq!(
+            stats.dice.get(&6),
+            Some(&DieStats {
+                rolled: 4,
+                total: 16,
+                highest: 2,
+                lowest: 1,
+            })
+        );
+        assert_eq!(stats.dice.get(&20), d20s(2, 21, 1, 1).dice.get(&20));
+        // Fate and custom dice aren't tracked
+        assert_eq!(stats.dice.len(), 2);
+    }
+
+    #[test]
+    fn merges_each_die_size() {
+        let mut stats = d20s(3, 30, 1, 0);
+        let mut other = d20s(2, 5, 0, 1);
+        other.dice.insert(
+            6,
+            DieStats {
+                rolled: 1,
+                total: 6,
+                highest: 1,
+                lowest: 0,
+            },
+        );
+        stats.merge(&other);
+        assert_eq!(stats.dice[&20], d20s(5, 35, 1, 1).dice[&20]);
+        assert_eq!(stats.dice[&6], other.dice[&6]);
+    }
+
+    #[test]
+    fn leaderboard_needs_enough_d20s() {
+        let users = vec![
+            (String::from("alice"), d20s(10, 80, 0, 2)),
+            (String::from("bob"), d20s(10, 150, 2, 0)),
+            (String::from("carol"), d20s(9, 180, 9, 0)),
+        ];
+        assert_eq!(
+            format_leaderboard(&users),
+            "**Luck leaderboard**\n\
+             1. bob: average **15.00** over 10 d20s, 2 nat 20s, 0 nat 1s\n\
+             2. alice: average **8.00** over 10 d20s, 0 nat 20s, 2 nat 1s"
+        );
+        assert_eq!(
+            format_leaderboard(&users[2..]),
+            "Nobody has rolled 10 d20s yet, so there's no leaderboard."
+        );
+    }
+}
@@ -1,25 +1,336 @@
-use anyhow::anyhow;
-use aws::{
-    add_or_update_action, create_aws_client, create_credentials_provider, delete_action,
-    get_action_roll, increment_hehs, Action, WakeBotDbError,
+use actions::{
+    expand_references, fill_defaults, fill_placeholders, filter_actions, format_action,
+    format_action_list, has_references, parse_list_args,
 };
+use anyhow::anyhow;
+use aws::{create_aws_client, create_credentials_provider, DynamoDbStorage};
+use embeds::{error_embed, repeated_roll_embed, roll_embed, RollEmbed};
+use errors::format_error;
 use fancy_regex::Regex;
-use rolls::{format_rolls_result_new, interpret_rolls, DICE_COMMAND_REGEX};
+use history::{format_history, format_history_entry, HistoryEntry, RollHistory, RollRequest};
+use luck::{format_leaderboard, format_luck, LuckStats};
+use memory::MemoryStorage;
+use messages::{direct_message, reply};
+use rand::rngs::OsRng;
+use rand::Rng;
+use rolls::{
+    find_inline_rolls, format_inline_rolls, format_repeated_rolls, format_rolls_in_full,
+    format_rolls_result_new, interpret_repeated_rolls, interpret_rolls_with, parse_rolls,
+    seeded_rng, split_expressions, split_message_label, split_repeat, validate_rolls, CritProfile,
+    D20Mode, DiceRng, RollOptions, RollStringResult, DICE_COMMAND_REGEX,
+};
 use serenity::async_trait;
+use serenity::builder::CreateEmbed;
 use serenity::model::channel::Message;
 use serenity::model::gateway::Ready;
+use serenity::model::id::UserId;
 use serenity::model::prelude::GuildChannel;
 use serenity::prelude::*;
 use shunting::{MathContext, ShuntingParser};
+use sqlite::SqliteStorage;
+use stats::{format_stats, roll_stats, split_target};
 use std::collections::HashMap;
+use storage::{Action, ActionScope, RollRecord, Storage, WakeBotDbError};
 
+mod actions;
 mod aws;
+mod embeds;
 mod errors;
+mod history;
+mod luck;
+mod memory;
+mod messages;
 mod rolls;
+mod sqlite;
+mod stats;
+mod storage;
+
+// Letters that are hard to mix up, for roll IDs people might type back in
+const ROLL_ID_CHARACTERS: &[u8] = b"abcdefghjkmnpqrstuvwxyz23456789";
+
+// How many IDs to try before giving up on logging a roll
+const MAX_ROLL_ID_ATTEMPTS: usize = 5;
+
+fn new_roll_id() -> String {
+    (0..8)
+        .map(|_| ROLL_ID_CHARACTERS[OsRng.gen_range(0..ROLL_ID_CHARACTERS.len())] as char)
+        .collect()
+}
+
+// Discord won't take more embeds than this in one message, or more text than this across all of them
+const MAX_EMBEDS: usize = 10;
+const MAX_EMBED_LENGTH: usize = 6000;
+
+struct RollResponse<'a> {
+    // Plain text version, used for DMs and whenever the embeds won't fit
+    text: String,
+    // Missing when the rolls won't fit in embeds
+    embeds: Option<Vec<CreateEmbed>>,
+    // Every roll made, for the roll history and luck stats
+    rolls: Vec<RollStringResult<'a>>,
+}
+
+// Rolls each expression in a message separately, giving every one its own section of the reply
+fn roll_response<'a>(
+    input: &'a str,
+    options: &RollOptions,
+    show_sum: bool,
+    show_sorted: bool,
+    rng: &mut dyn DiceRng,
+) -> RollResponse<'a> {
+    let mut sections = vec![];
+    let mut embeds = vec![];
+    let mut rolls = vec![];
+    for roll_str in split_expressions(input) {
+        if let Some((count, repeated)) = split_repeat(roll_str) {
+            match interpret_repeated_rolls(count, repeated, options, rng) {
+                Ok(results) => {
+                    sections.push(format_repeated_rolls(
+                        roll_str,
+                        &results,
+                        show_sum,
+                        show_sorted,
+                    ));
+                    embeds.push(repeated_roll_embed(
+                        roll_str,
+                        &results,
+                        show_sum,
+                        show_sorted,
+                    ));
+                    rolls.extend(results);
+                }
+                Err(e) => {
+                    // The repeat prefix comes off the front, leaving the rest of the roll in place
+                    let e = e.offset_by(roll_str.len() - repeated.len());
+                    sections.push(format_error(roll_str, &e));
+                    embeds.push(Some(error_embed(roll_str, &e)));
+                }
+            }
+        } else {
+            match interpret_rolls_with(roll_str, options, rng) {
+                Ok(result) => {
+                    embeds.push(roll_embed(&result));
+                    sections.push(format_rolls_result_new(result.clone()));
+                    rolls.push(result);
+                }
+                Err(e) => {
+                    sections.push(format_error(roll_str, &e));
+                    embeds.push(Some(error_embed(roll_str, &e)));
+                }
+            }
+        }
+    }
+    // Any roll too big for an embed of its own means the plain text is sent instead, as it is when the
+    // embeds together go over Discord's limits
+    let embeds = embeds
+        .into_iter()
+        .collect::<Option<Vec<RollEmbed>>>()
+        .filter(|embeds| {
+            embeds.len() <= MAX_EMBEDS
+                && embeds.iter().map(|embed| embed.length).sum::<usize>() <= MAX_EMBED_LENGTH
+        })
+        .map(|embeds| embeds.into_iter().map(|embed| embed.embed).collect());
+    RollResponse {
+        text: sections.join("\n\n"),
+        embeds,
+        rolls,
+    }
+}
 
 struct Handler {
-    aws_client: aws_sdk_dynamodb::Client,
+    storage: Box<dyn Storage>,
     allowed_channels: Vec<String>,
+    history: RollHistory,
+}
+
+impl Handler {
+    // A user's own crit profile takes priority over the channel's, falling back to the standard d20 rules
+    async fn crit_profile(&self, msg: &Message) -> CritProfile {
+        let scopes = [
+            format!("user:{}", msg.author.id),
+            format!("channel:{}", msg.channel_id),
+        ];
+        for scope in scopes {
+            if let Ok(Some(profile)) = self.storage.get_crit_profile(&scope).await {
+                if let Some(profile) = CritProfile::parse(&profile) {
+                    return profile;
+                }
+            }
+        }
+        CritProfile::default()
+    }
+
+    // Whether the author of a message has Manage Channels where they sent it. Direct messages only affect
+    // whoever sent them, so they always can.
+    async fn can_manage_channel(ctx: &Context, msg: &Message) -> bool {
+        let guild_id = match msg.guild_id {
+            Some(guild_id) => guild_id,
+            None => return true,
+        };
+        let guild = guild_id.to_partial_guild(&ctx.http).await;
+        let channel = msg.channel_id.to_channel(ctx).await;
+        let member = guild_id.member(ctx, msg.author.id).await;
+        match (guild, channel.map(|channel| channel.guild()), member) {
+            (Ok(guild), Ok(Some(channel)), Ok(member)) => guild
+                .user_permissions_in(&channel, &member)
+                .is_ok_and(|permissions| permissions.manage_channels()),
+            _ => false,
+        }
+    }
+
+    // Adds rolls to the roller's luck stats, logs them with every die for !verify and remembers them for !r
+    // and !history, returning the roll ID if logging worked
+    async fn record_rolls(
+        &self,
+        msg: &Message,
+        request: RollRequest,
+        rolls: &[RollStringResult<'_>],
+        seed: u64,
+    ) -> Option<String> {
+        let mut luck = LuckStats::default();
+        luck.add_rolls(rolls);
+        self.update_luck(msg, &luck).await;
+        // When every expression failed there's no result to check, so there's no ID either
+        let roll_id = if rolls.is_empty() {
+            None
+        } else {
+            self.log_roll(
+                msg,
+                &request.roll,
+                &format_rolls_in_full(rolls),
+                seed,
+                request.is_private,
+            )
+            .await
+        };
+        self.history.push(
+            msg.author.id,
+            msg.channel_id,
+            HistoryEntry {
+                totals: rolls.iter().map(|result| result.total).collect(),
+                request,
+                roll_id: roll_id.clone(),
+            },
+        );
+        roll_id
+    }
+
+    // Rolls and sends the result, logging it for !verify and remembering it for !r and !history
+    async fn roll_and_reply(&self, ctx: &Context, msg: &Message, request: RollRequest) {
+        let seed = OsRng.gen::<u64>();
+        let RollResponse {
+            text: mut response_str,
+            embeds,
+            rolls,
+        } = roll_response(
+            &request.roll,
+            &request.options,
+            request.show_sum,
+            request.show_sorted,
+            &mut seeded_rng(seed),
+        );
+        let is_private = request.is_private;
+        let roll_id = self.record_rolls(msg, request, &rolls, seed).await;
+        let roll_id_line = roll_id
+            .as_ref()
+            .map(|roll_id| format!("Roll ID: `{}`", roll_id))
+            .unwrap_or_default();
+        if !roll_id_line.is_empty() {
+            response_str += &format!("\n{}", roll_id_line);
+        }
+        if is_private {
+            let link = msg.link();
+            println!("Sent to {}:\n{}", msg.author.name, response_str);
+            direct_message(ctx, &msg.author, format!("{}\n{}", link, response_str)).await;
+            // Leave the ID where everyone can see it, so the result can be checked later
+            if let Some(roll_id) = roll_id {
+                reply(
+                    ctx,
+                    msg,
+                    format!("Rolled privately. Roll ID: `{}`", roll_id),
+                )
+                .await;
+            }
+        } else if let Some(embeds) = embeds {
+            let result = msg
+                .channel_id
+                .send_message(&ctx.http, |m| {
+                    m.reference_message(msg)
+                        .content(roll_id_line)
+                        .set_embeds(embeds)
+                })
+                .await;
+            if let Err(e) = result {
+                println!("There was a problem sending result: {}", e);
+            }
+        } else {
+            // Long text is split over a few messages, or attached as a file
+            reply(ctx, msg, response_str).await;
+        }
+    }
+
+    // Actions are saved per server, with direct messages counting as a server of their own
+    fn action_guild(msg: &Message) -> String {
+        msg.guild_id
+            .map_or_else(|| String::from("dm"), |guild_id| guild_id.to_string())
+    }
+
+    // Luck stats are kept per server, so the leaderboard only compares people who play together
+    fn luck_scope(msg: &Message, user_id: UserId) -> String {
+        match msg.guild_id {
+            Some(guild_id) => format!("{}:{}", guild_id, user_id),
+            None => format!("dm:{}", user_id),
+        }
+    }
+
+    async fn update_luck(&self, msg: &Message, luck: &LuckStats) {
+        if luck.is_empty() {
+            return;
+        }
+        let scope = Handler::luck_scope(msg, msg.author.id);
+        if let Err(e) = self
+            .storage
+            .add_luck_stats(&scope, &msg.author.name, luck)
+            .await
+        {
+            println!("There was a problem saving luck stats: {}", e);
+        }
+    }
+
+    // Logs a roll so it can be checked later with !verify, returning the ID to show with it
+    async fn log_roll(
+        &self,
+        msg: &Message,
+        expression: &str,
+        result: &str,
+        seed: u64,
+        is_private: bool,
+    ) -> Option<String> {
+        let mut record = RollRecord {
+            id: new_roll_id(),
+            expression: String::from(expression),
+            user_id: msg.author.id.to_string(),
+            user_name: msg.author.name.clone(),
+            channel_id: msg.channel_id.to_string(),
+            timestamp: msg.timestamp.unix_timestamp(),
+            result: String::from(result),
+            seed,
+            is_private,
+        };
+        // An ID that's already taken just gets another, so no earlier record is ever overwritten
+        for _ in 0..MAX_ROLL_ID_ATTEMPTS {
+            match self.storage.add_roll_record(&record).await {
+                Ok(_) => return Some(record.id),
+                Err(WakeBotDbError::AlreadyExists) => record.id = new_roll_id(),
+                Err(e) => {
+                    println!("There was a problem logging roll: {}", e);
+                    return None;
+                }
+            }
+        }
+        println!("There was a problem logging roll: no free roll ID found");
+        None
+    }
 }
 
 #[async_trait]
@@ -31,132 +342,549 @@ impl EventHandler for Handler {
         }
         if self.allowed_channels.contains(&msg.channel_id.to_string()) {
             if content.starts_with("!action ") {
-                let args = content.split(" ").collect::<Vec<&str>>();
+                let args = content.split_whitespace().collect::<Vec<&str>>();
                 if args.len() < 2 {
-                    msg.reply(&ctx.http, "Invalid request sent for action.\nTo add, format like: !action <name> <roll>\nTo use, format like: !action <name>").await.expect("Failed to reply");
+                    reply(&ctx, &msg, "Invalid request sent for action.\nTo add, format like: !action <name> <roll>\nTo add one only you can use: !action personal <name> <roll>\nTo use, format like: !action <name> [arguments]\nSaved rolls can use other actions and your variables from !set, like: !action attack !1d20+@str_mod\nTo see what's saved: !action list [filter] [page] or !action show <name>").await;
                 }
-                let action_name = String::from(args[1]);
+                // "!action personal <name> <roll>" saves an action only its owner can use, which takes
+                // priority over a server action with the same name
+                let is_personal =
+                    args[1].eq("personal") && args.len() > 3 && args[3].starts_with('!');
+                let action_name = String::from(if is_personal { args[2] } else { args[1] });
                 if action_name.eq("heh") {
-                    msg.reply(&ctx.http, "Cannot use action 'heh' due to Ed's laziness.")
-                        .await
-                        .expect("Failed to reply");
+                    reply(&ctx, &msg, "Cannot use action 'heh' due to Ed's laziness.").await;
                     return;
                 }
+                // Names are also what keeps lookups from reaching anything stored that isn't an action
                 let valid_action_regex = Regex::new(r"^[a-zA-Z0-9_-]+$").unwrap();
-                if !valid_action_regex.is_match(&action_name).unwrap_or(false) {
-                    msg.reply(&ctx.http, "Invalid action name")
-                        .await
-                        .expect("Failed to reply");
+                let is_valid_name = |name: &str| valid_action_regex.is_match(name).unwrap_or(false);
+                if !is_valid_name(&action_name) {
+                    reply(&ctx, &msg, "Invalid action name").await;
+                    return;
+                }
+                let guild = Handler::action_guild(&msg);
+                let user_id = msg.author.id.to_string();
+                if args[1].eq("list") {
+                    let (filter, page) = if let Some(list_args) = parse_list_args(&args[2..]) {
+                        list_args
+                    } else {
+                        reply(
+                            &ctx,
+                            &msg,
+                            "Invalid list request.\nFormat should be '!action list [filter] [page]'",
+                        )
+                        .await;
+                        return;
+                    };
+                    let response = match self.storage.get_visible_actions(&guild, &user_id).await {
+                        Ok(actions) => {
+                            format_action_list(&filter_actions(actions, filter), filter, page)
+                        }
+                        Err(_) => String::from("There was a problem while fetching actions."),
+                    };
+                    reply(&ctx, &msg, response).await;
+                    return;
+                }
+                if args[1].eq("show") && args.len() == 3 {
+                    if !is_valid_name(args[2]) {
+                        reply(&ctx, &msg, "Invalid action name").await;
+                        return;
+                    }
+                    let response = match self.storage.get_action(&guild, &user_id, args[2]).await {
+                        Ok(action) => format_action(&action, &user_id),
+                        Err(WakeBotDbError::NotFound(_)) => {
+                            format!("No action named '{}' found.", args[2])
+                        }
+                        Err(_) => String::from("There was a problem while fetching action."),
+                    };
+                    reply(&ctx, &msg, response).await;
                     return;
                 }
-                if args.len() == 2 {
-                    let roll = match get_action_roll(&self.aws_client, &action_name).await {
-                        Ok(r) => r,
+                // Saving always gives a roll starting with '!', so anything else after the name is arguments
+                if args.len() == 2
+                    || !(args[1].eq("delete") || is_personal || args[2].starts_with('!'))
+                {
+                    let stored_roll = match self
+                        .storage
+                        .get_action(&guild, &user_id, &action_name)
+                        .await
+                    {
+                        Ok(action) => action.roll,
                         Err(WakeBotDbError::NotFound(_)) => {
-                            msg.reply(
-                                &ctx.http,
+                            reply(
+                                &ctx,
+                                &msg,
                                 format!("No action named '{}' found.", action_name),
                             )
-                            .await
-                            .expect("Problem sending response");
+                            .await;
                             return;
                         }
                         _ => {
-                            msg.reply(
-                                &ctx.http,
+                            reply(
+                                &ctx,
+                                &msg,
                                 String::from("There was a problem while fetching action."),
                             )
-                            .await
-                            .expect("Problem sending response");
+                            .await;
                             return;
                         }
                     };
-                    let rolls_result = interpret_rolls(&roll, 0);
-                    if let Ok(result) = rolls_result {
-                        match msg.reply(&ctx.http, format_rolls_result_new(result)).await {
-                            Ok(_) => println!("Reply sent with result"),
-                            Err(e) => println!("There was a problem sending result: {}", e),
-                        };
-                    }
+                    let roll = match fill_placeholders(&stored_roll, &args[2..]) {
+                        Ok(roll) => roll,
+                        Err(e) => {
+                            reply(&ctx, &msg, format_error(&stored_roll, &e)).await;
+                            return;
+                        }
+                    };
+                    let roll = match expand_references(
+                        self.storage.as_ref(),
+                        &guild,
+                        &user_id,
+                        &action_name,
+                        &roll,
+                    )
+                    .await
+                    {
+                        Ok(roll) => roll,
+                        Err(e) => {
+                            reply(&ctx, &msg, format_error(&roll, &e)).await;
+                            return;
+                        }
+                    };
+                    let options = RollOptions {
+                        crit_profile: self.crit_profile(&msg).await,
+                        ..Default::default()
+                    };
+                    let request = RollRequest {
+                        // Include the arguments so history shows what was used
+                        name: args[1..].join(" "),
+                        roll,
+                        options,
+                        show_sum: false,
+                        show_sorted: false,
+                        is_private: false,
+                    };
+                    self.roll_and_reply(&ctx, &msg, request).await;
                 } else if args[1].eq("delete") {
                     if args.len() > 3 {
-                        msg.reply(
-                            &ctx.http,
+                        reply(
+                            &ctx,
+                            &msg,
                             "Invalid delete request.\nFormat should be '!action delete <name>'",
                         )
-                        .await
-                        .expect("Failed to reply");
+                        .await;
                         return;
                     }
                     if let Some(name) = args.get(2) {
-                        let item_existed = get_action_roll(&self.aws_client, name).await.is_ok();
-                        if !item_existed {
-                            msg.reply(&ctx.http, format!("Action '{}' does not exist.", name))
-                                .await
-                                .expect("Failed to reply");
+                        if !is_valid_name(name) {
+                            reply(&ctx, &msg, "Invalid action name").await;
                             return;
                         }
-                        if let Ok(_) = delete_action(&self.aws_client, name).await {
-                            msg.reply(&ctx.http, "Action deleted.")
-                                .await
-                                .expect("Failed to reply");
+                        // Deletes whichever action the user would get by using the name
+                        let action = match self.storage.get_action(&guild, &user_id, name).await {
+                            Ok(action) => action,
+                            Err(_) => {
+                                reply(&ctx, &msg, format!("Action '{}' does not exist.", name))
+                                    .await;
+                                return;
+                            }
+                        };
+                        if action.scope == ActionScope::Global {
+                            reply(
+                                &ctx,
+                                &msg,
+                                format!(
+                                    "Action '{}' was saved before actions had owners, so it can't be deleted.",
+                                    name
+                                ),
+                            )
+                            .await;
+                            return;
+                        }
+                        if !action.can_modify(&user_id) {
+                            reply(
+                                &ctx,
+                                &msg,
+                                format!(
+                                    "Only the person who saved action '{}' can delete it.",
+                                    name
+                                ),
+                            )
+                            .await;
+                            return;
+                        }
+                        if self
+                            .storage
+                            .delete_action(&action.scope, name)
+                            .await
+                            .is_ok()
+                        {
+                            reply(&ctx, &msg, "Action deleted.").await;
                             return;
                         } else {
-                            msg.reply(&ctx.http, "Failed to delete action.")
-                                .await
-                                .expect("Failed to reply");
+                            reply(&ctx, &msg, "Failed to delete action.").await;
                             return;
                         }
                     } else {
-                        msg.reply(
-                            &ctx.http,
+                        reply(
+                            &ctx,
+                            &msg,
                             "Invalid delete request.\nFormat should be '!action delete <name>'",
                         )
-                        .await
-                        .expect("Failed to reply");
+                        .await;
                         return;
                     }
                 } else {
-                    let roll_input = args[2..].join(" ");
+                    let roll_input = args[if is_personal { 3 } else { 2 }..].join(" ");
                     // Use regex to validate roll string
+                    // Any placeholders are checked with their defaults filled in
+                    let filled_roll = match fill_defaults(&roll_input[1..]) {
+                        Ok(roll) => roll,
+                        Err(e) => {
+                            reply(&ctx, &msg, format_error(&roll_input[1..], &e)).await;
+                            return;
+                        }
+                    };
+                    // A roll of only references, like "@attack+@damage", needs no dice of its own. Labels
+                    // aren't expanded, so references in one don't count.
                     let roll_regex = Regex::new(DICE_COMMAND_REGEX).unwrap();
-                    if !roll_regex.is_match(&roll_input).unwrap_or(false) {
-                        msg.reply(&ctx.http, "Invalid roll string")
-                            .await
-                            .expect("Failed to reply");
+                    if !has_references(split_message_label(&roll_input[1..]).0)
+                        && !roll_regex
+                            .is_match(&format!("!{}", filled_roll))
+                            .unwrap_or(false)
+                    {
+                        reply(&ctx, &msg, "Invalid roll string").await;
+                        return;
+                    }
+                    if let Err(e) = validate_rolls(&filled_roll) {
+                        reply(&ctx, &msg, format_error(&filled_roll, &e)).await;
                         return;
                     }
-                    let item_existed = get_action_roll(&self.aws_client, &action_name)
+                    let scope = if is_personal {
+                        ActionScope::Personal {
+                            guild,
+                            user_id: user_id.clone(),
+                        }
+                    } else {
+                        ActionScope::Server { guild }
+                    };
+                    let item_existed = match self
+                        .storage
+                        .get_scoped_action(&scope, &action_name)
                         .await
-                        .is_ok();
-
-                    if let Ok(_) = add_or_update_action(
-                        &self.aws_client,
-                        &Action {
-                            name: &action_name,
-                            roll: &roll_input,
-                        },
-                    )
-                    .await
+                    {
+                        Ok(existing) if !existing.can_modify(&user_id) => {
+                            reply(&ctx, &msg, format!("Action '{}' belongs to someone else. Use '!action personal {} <roll>' to save your own.", action_name, action_name)).await;
+                            return;
+                        }
+                        Ok(_) => true,
+                        Err(WakeBotDbError::NotFound(_)) => false,
+                        Err(_) => {
+                            reply(&ctx, &msg, "There was a problem while fetching action.").await;
+                            return;
+                        }
+                    };
+
+                    if self
+                        .storage
+                        .add_or_update_action(&Action::new(
+                            &action_name,
+                            &roll_input,
+                            scope,
+                            &user_id,
+                        ))
+                        .await
+                        .is_ok()
                     {
                         // Send msg
-                        msg.reply(
-                            &ctx.http,
+                        reply(
+                            &ctx,
+                            &msg,
                             format!(
-                                "Action '{}' {}.",
+                                "{} '{}' {}.",
+                                if is_personal {
+                                    "Personal action"
+                                } else {
+                                    "Action"
+                                },
                                 action_name,
                                 if item_existed { "updated" } else { "created" }
                             ),
                         )
-                        .await
-                        .expect("Failed to reply");
+                        .await;
                         return;
                     } else {
-                        msg.reply(&ctx.http, "Failed to add action.")
-                            .await
-                            .expect("Failed to reply");
+                        reply(&ctx, &msg, "Failed to add action.").await;
+                        return;
+                    }
+                }
+            }
+            // Variables are per user and server, for rolls to use as "$name" or "@name"
+            if content.eq("!set") || content.starts_with("!set ") || content.starts_with("!unset ")
+            {
+                let args = content.split_whitespace().collect::<Vec<&str>>();
+                let guild = Handler::action_guild(&msg);
+                let user_id = msg.author.id.to_string();
+                let valid_variable_regex = Regex::new(r"^[a-zA-Z0-9_]+$").unwrap();
+                if let Some(name) = args.get(1) {
+                    if !valid_variable_regex.is_match(name).unwrap_or(false) {
+                        reply(
+                            &ctx,
+                            &msg,
+                            "Invalid variable name. Use only letters, numbers and '_'.",
+                        )
+                        .await;
+                        return;
+                    }
+                }
+                let response = match args[..] {
+                    ["!set"] => match self.storage.list_variables(&guild, &user_id).await {
+                        Ok(variables) if variables.is_empty() => {
+                            String::from("You haven't set any variables here.")
+                        }
+                        Ok(variables) => variables
+                            .iter()
+                            .map(|(name, value)| format!("`{}` = `{}`", name, value))
+                            .collect::<Vec<String>>()
+                            .join("\n"),
+                        Err(_) => String::from("There was a problem while fetching variables."),
+                    },
+                    ["!set", name] => match self.storage.get_variable(&guild, &user_id, name).await
+                    {
+                        Ok(Some(value)) => format!("`{}` = `{}`", name, value),
+                        Ok(None) => format!("You haven't set '{}' here.", name),
+                        Err(_) => String::from("There was a problem while fetching variable."),
+                    },
+                    ["!unset", name] => {
+                        match self.storage.delete_variable(&guild, &user_id, name).await {
+                            Ok(_) => format!("Variable '{}' removed.", name),
+                            Err(_) => String::from("Failed to remove variable."),
+                        }
+                    }
+                    ["!set", name, ..] => {
+                        let value = args[2..].join(" ");
+                        // References are only looked up when the value is used, so variables can
+                        // be set in any order
+                        let checked = match fill_defaults(&value) {
+                            Ok(filled) => {
+                                parse_rolls(&filled).map_err(|e| format_error(&filled, &e))
+                            }
+                            Err(e) => Err(format_error(&value, &e)),
+                        };
+                        match checked {
+                            Err(error) => error,
+                            Ok(_) => match self
+                                .storage
+                                .set_variable(&guild, &user_id, name, &value)
+                                .await
+                            {
+                                Ok(_) => format!("Variable '{}' set to `{}`.", name, value),
+                                Err(_) => String::from("Failed to set variable."),
+                            },
+                        }
+                    }
+                    _ => String::from(
+                        "Invalid variable request.\nFormat should be '!set <name> <value>'",
+                    ),
+                };
+                reply(&ctx, &msg, response).await;
+                return;
+            }
+            if content.eq("!crit") || content.starts_with("!crit ") {
+                let args = content.split_whitespace().collect::<Vec<&str>>();
+                let (scope, scope_name, profile_arg) = match args[1..] {
+                    [] => {
+                        reply(
+                            &ctx,
+                            &msg,
+                            format!("Your crit profile is '{}'.", self.crit_profile(&msg).await),
+                        )
+                        .await;
+                        return;
+                    }
+                    ["channel", profile] => {
+                        // Changing the rules for everyone in a channel is left to whoever can manage it
+                        if !Handler::can_manage_channel(&ctx, &msg).await {
+                            reply(
+                                &ctx,
+                                &msg,
+                                "Only people who can manage this channel can change its crit profile.",
+                            )
+                            .await;
+                            return;
+                        }
+                        (
+                            format!("channel:{}", msg.channel_id),
+                            "this channel",
+                            profile,
+                        )
+                    }
+                    [profile] => (format!("user:{}", msg.author.id), "you", profile),
+                    _ => {
+                        reply(&ctx, &msg, "Invalid crit request.\nFormat should be '!crit <profile>' or '!crit channel <profile>'").await;
+                        return;
+                    }
+                };
+                let profile = if let Some(profile) = CritProfile::parse(profile_arg) {
+                    profile
+                } else {
+                    reply(&ctx, &msg, "Invalid crit profile. Use 'standard', 'max', 'none' or a number like 19 to crit on 19-20 with d20s.").await;
+                    return;
+                };
+                if self
+                    .storage
+                    .set_crit_profile(&scope, &profile.to_string())
+                    .await
+                    .is_ok()
+                {
+                    reply(
+                        &ctx,
+                        &msg,
+                        format!("Crit profile set to '{}' for {}.", profile, scope_name),
+                    )
+                    .await;
+                } else {
+                    reply(&ctx, &msg, "Failed to set crit profile.").await;
+                }
+                return;
+            }
+            if content.starts_with("!stats ") || content.starts_with("!odds ") {
+                let (command, roll) = content.split_once(' ').unwrap();
+                let roll = roll.trim();
+                let response = if command == "!odds" {
+                    split_target(roll).and_then(|(expression, target)| {
+                        roll_stats(expression)
+                            .map(|stats| format_stats(expression, &stats, Some(&target)))
+                    })
+                } else {
+                    roll_stats(roll).map(|stats| format_stats(roll, &stats, None))
+                };
+                let response = match response {
+                    Ok(response) => response,
+                    Err(e) => format_error(roll, &e),
+                };
+                reply(&ctx, &msg, response).await;
+                return;
+            }
+            if content.eq("!r") || content.eq("!reroll") {
+                match self.history.last(msg.author.id, msg.channel_id) {
+                    Some(entry) => self.roll_and_reply(&ctx, &msg, entry.request).await,
+                    None => {
+                        reply(&ctx, &msg, "You haven't rolled anything here yet.").await;
+                    }
+                }
+                return;
+            }
+            if content.eq("!last") {
+                let response = match self.history.last(msg.author.id, msg.channel_id) {
+                    Some(entry) => format_history_entry(&entry),
+                    None => String::from("You haven't rolled anything here yet."),
+                };
+                reply(&ctx, &msg, response).await;
+                return;
+            }
+            if content.eq("!history") {
+                let entries = self.history.recent(msg.author.id, msg.channel_id);
+                let response = if entries.is_empty() {
+                    String::from("You haven't rolled anything here yet.")
+                } else {
+                    format_history(&entries)
+                };
+                reply(&ctx, &msg, response).await;
+                return;
+            }
+            if content.eq("!luck") || content.starts_with("!luck ") {
+                let response = if content.eq("!luck leaderboard") {
+                    let prefix = match msg.guild_id {
+                        Some(guild_id) => format!("{}:", guild_id),
+                        None => String::from("dm:"),
+                    };
+                    match self.storage.get_all_luck_stats(&prefix).await {
+                        Ok(users) => format_leaderboard(&users),
+                        Err(_) => String::from("There was a problem while fetching luck stats."),
+                    }
+                } else {
+                    let user = msg.mentions.first().unwrap_or(&msg.author);
+                    let scope = Handler::luck_scope(&msg, user.id);
+                    match self.storage.get_luck_stats(&scope).await {
+                        Ok(stats) => format_luck(&user.name, &stats),
+                        Err(_) => String::from("There was a problem while fetching luck stats."),
+                    }
+                };
+                reply(&ctx, &msg, response).await;
+                return;
+            }
+            if content.starts_with("!verify ") {
+                let roll_id = content["!verify ".len()..].trim();
+                let record = match self.storage.get_roll_record(roll_id).await {
+                    Ok(record) => record,
+                    Err(WakeBotDbError::NotFound(_)) => {
+                        reply(&ctx, &msg, format!("No roll with ID '{}' found.", roll_id)).await;
+                        return;
+                    }
+                    Err(_) => {
+                        reply(&ctx, &msg, "There was a problem while fetching roll.").await;
                         return;
                     }
+                };
+                let heading = format!(
+                    "Roll `{}` by **{}** in <#{}> at <t:{}:f>",
+                    record.id, record.user_name, record.channel_id, record.timestamp
+                );
+                // Private results only ever go to the person who rolled them, and only by DM
+                if !record.is_private {
+                    reply(&ctx, &msg, format!("{}\n{}", heading, record.result)).await;
+                } else if record.user_id == msg.author.id.to_string() {
+                    direct_message(&ctx, &msg.author, format!("{}\n{}", heading, record.result))
+                        .await;
+                    reply(&ctx, &msg, "Sent you the details of your private roll.").await;
+                } else {
+                    reply(
+                        &ctx,
+                        &msg,
+                        format!(
+                            "{}\nThis roll was private, so only the person who rolled it can see the result.",
+                            heading
+                        ),
+                    )
+                    .await;
+                }
+                return;
+            }
+            // Rolls written inline in ordinary chat, like "I attack [[1d20+5]]"
+            if !content.starts_with("!") {
+                let inline_rolls = find_inline_rolls(content);
+                if !inline_rolls.is_empty() {
+                    let options = RollOptions {
+                        crit_profile: self.crit_profile(&msg).await,
+                        ..Default::default()
+                    };
+                    let seed = OsRng.gen::<u64>();
+                    let (mut response_str, rolls) = format_inline_rolls(
+                        content,
+                        &inline_rolls,
+                        &options,
+                        &mut seeded_rng(seed),
+                    );
+                    // !r rolls them again as an ordinary roll of every expression
+                    let roll = inline_rolls
+                        .iter()
+                        .map(|span| &content[span.start + 2..span.end - 2])
+                        .collect::<Vec<&str>>()
+                        .join("; ");
+                    let request = RollRequest {
+                        name: roll.clone(),
+                        roll,
+                        options,
+                        show_sum: false,
+                        show_sorted: false,
+                        is_private: false,
+                    };
+                    if let Some(roll_id) = self.record_rolls(&msg, request, &rolls, seed).await {
+                        response_str += &format!("\nRoll ID: `{}`", roll_id);
+                    }
+                    reply(&ctx, &msg, response_str).await;
+                    return;
                 }
             }
             let dice_command_regex = Regex::new(DICE_COMMAND_REGEX).unwrap();
@@ -180,42 +908,48 @@ impl EventHandler for Handler {
                     HashMap::new()
                 };
                 let is_private = *commands.get("private").or(Some(&false)).unwrap();
+                let is_flag_set = |flag: &str| *commands.get(flag).or(Some(&false)).unwrap();
+                let show_sum = is_flag_set("sum");
+                let show_sorted = is_flag_set("sort");
+                let options = RollOptions {
+                    crit_profile: self.crit_profile(&msg).await,
+                    d20_mode: if is_flag_set("elven") {
+                        D20Mode::ElvenAccuracy
+                    } else if is_flag_set("adv") {
+                        D20Mode::Advantage
+                    } else if is_flag_set("dis") {
+                        D20Mode::Disadvantage
+                    } else {
+                        D20Mode::Straight
+                    },
+                    halfling_luck: is_flag_set("halfling"),
+                };
 
-                let response_str = match interpret_rolls(&content[1..commands_start], 0) {
-                    Ok(result) => format_rolls_result_new(result),
-                    Err(e) => format!("Err: {}", e),
+                let roll = String::from(&content[1..commands_start]);
+                let request = RollRequest {
+                    name: roll.clone(),
+                    roll,
+                    options,
+                    show_sum,
+                    show_sorted,
+                    is_private,
                 };
-                if is_private {
-                    let link = msg.link();
-                    println!("Sent to {}:\n{}", msg.author.name, response_str);
-                    msg.author
-                        .direct_message(&ctx.http, |m| {
-                            m.content(format!("{}\n{}", link, response_str))
-                        })
-                        .await
-                        .expect("Failed to direct message.");
-                } else {
-                    msg.reply(&ctx.http, response_str)
-                        .await
-                        .expect("Failed to reply.");
-                }
+                self.roll_and_reply(&ctx, &msg, request).await;
                 return;
             }
 
             if content.starts_with("!") {
-                let exp = ShuntingParser::parse_str(&content[1..]);
-                let res = MathContext::new().eval(&exp.unwrap());
-                if res.is_ok() {
-                    msg.reply(
-                        &ctx.http,
-                        format!(
-                            "{} = **{}**",
-                            content[1..].replace("*", r"\*"),
-                            res.unwrap()
-                        ),
+                // Anything that isn't valid math just falls through to the commands below
+                let res = ShuntingParser::parse_str(&content[1..])
+                    .ok()
+                    .and_then(|exp| MathContext::new().eval(&exp).ok());
+                if let Some(res) = res {
+                    reply(
+                        &ctx,
+                        &msg,
+                        format!("{} = **{}**", content[1..].replace("*", r"\*"), res),
                     )
-                    .await
-                    .expect("Failed to reply");
+                    .await;
                     return;
                 }
             }
@@ -241,31 +975,29 @@ impl EventHandler for Handler {
             // }
 
             if content.eq("!heh") {
-                let heh_count = if let Ok(n) = increment_hehs(&self.aws_client).await {
+                let heh_count = if let Ok(n) = self.storage.increment_counter("heh").await {
                     n
                 } else {
                     // Throw error
-                    msg.reply(&ctx.http, "Heh, failed to get 'heh' count.")
-                        .await
-                        .expect("Failed to reply");
+                    reply(&ctx, &msg, "Heh, failed to get 'heh' count.").await;
                     return;
                 };
-                msg.reply(
-                    &ctx.http,
+                reply(
+                    &ctx,
+                    &msg,
                     format!("Heh, we've counted {} 'heh's.", heh_count),
                 )
-                .await
-                .expect("Failed to reply");
+                .await;
                 return;
             }
 
             if content.to_lowercase().eq("!wakebotsucks") {
-                msg.reply(
-                    &ctx.http,
+                reply(
+                    &ctx,
+                    &msg,
                     "https://y.yarn.co/ac2e41da-773a-4ae9-8012-b8c235994f9c_text.gif",
                 )
-                .await
-                .expect("Failed to reply");
+                .await;
                 return;
             }
         }
@@ -302,25 +1034,47 @@ pub async fn serenity(
     let intents =
         GatewayIntents::GUILDS | GatewayIntents::GUILD_MESSAGES | GatewayIntents::MESSAGE_CONTENT;
 
-    let aws_access_key = if let Some(id) = secret_store.get("AWS_ACCESS_KEY_ID") {
-        id
-    } else {
-        return Err(anyhow!("'AWS_ACCESS_KEY_ID' was not found").into());
-    };
+    // DynamoDB is used unless another backend is picked, so existing deployments carry on as before
+    let storage: Box<dyn Storage> = match secret_store.get("STORAGE_BACKEND").as_deref() {
+        None | Some("dynamodb") => {
+            let aws_access_key = if let Some(id) = secret_store.get("AWS_ACCESS_KEY_ID") {
+                id
+            } else {
+                return Err(anyhow!("'AWS_ACCESS_KEY_ID' was not found").into());
+            };
 
-    let aws_secret_access_key = if let Some(id) = secret_store.get("AWS_SECRET_ACCESS_KEY") {
-        id
-    } else {
-        return Err(anyhow!("'AWS_SECRET_ACCESS_KEY' was not found").into());
-    };
+            let aws_secret_access_key = if let Some(id) = secret_store.get("AWS_SECRET_ACCESS_KEY")
+            {
+                id
+            } else {
+                return Err(anyhow!("'AWS_SECRET_ACCESS_KEY' was not found").into());
+            };
 
-    let aws_creds = create_credentials_provider(&aws_access_key, &aws_secret_access_key);
-    let aws_client = create_aws_client(aws_creds).await;
+            let aws_creds = create_credentials_provider(&aws_access_key, &aws_secret_access_key);
+            let storage = DynamoDbStorage::new(create_aws_client(aws_creds).await);
+            if let Err(e) = storage.create_tables().await {
+                return Err(anyhow!("Failed to set up the DynamoDB tables: {}", e).into());
+            }
+            Box::new(storage)
+        }
+        Some("sqlite") => {
+            let path = secret_store
+                .get("SQLITE_PATH")
+                .unwrap_or_else(|| String::from("wakebot.db"));
+            match SqliteStorage::open(&path) {
+                Ok(storage) => Box::new(storage),
+                Err(e) => return Err(anyhow!("Failed to open '{}': {}", path, e).into()),
+            }
+        }
+        Some("memory") => Box::new(MemoryStorage::default()),
+        Some(other) => return Err(anyhow!("Unknown storage backend '{}'", other).into()),
+    };
 
     let mut client = Client::builder(&discord_token, intents)
         .event_handler(Handler {
-            aws_client,
+            storage,
             allowed_channels: vec![outsiders_channel_id, test_channel_id],
+            history: RollHistory::default(),
         })
         .await
         .expect("Err creating client");
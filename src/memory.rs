@@ -0,0 +1,250 @@
+use crate::luck::LuckStats;
+use crate::storage::{
+    action_not_found, roll_not_found, Action, ActionScope, RollRecord, Storage, WakeBotDbError,
+};
+use async_trait::async_trait;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+
+// Keeps everything in memory, so nothing survives a restart. Handy for trying the bot out and for tests.
+#[derive(Default)]
+pub struct MemoryStorage {
+    // Keyed by each action's scoped key
+    actions: Mutex<HashMap<String, Action>>,
+    counters: Mutex<HashMap<String, i32>>,
+    crit_profiles: Mutex<HashMap<String, String>>,
+    roll_records: Mutex<HashMap<String, RollRecord>>,
+    // Keyed by guild and user, each holding that user's variables by name
+    variables: Mutex<HashMap<(String, String), BTreeMap<String, String>>>,
+    // Keyed by scope, holding the user's name alongside their stats
+    luck_stats: Mutex<HashMap<String, (String, LuckStats)>>,
+}
+
+#[async_trait]
+impl Storage for MemoryStorage {
+    async fn get_scoped_action(
+        &self,
+        scope: &ActionScope,
+        action_name: &str,
+    ) -> Result<Action, WakeBotDbError> {
+        self.actions
+            .lock()
+            .unwrap()
+            .get(&scope.key(action_name))
+            .cloned()
+            .ok_or_else(action_not_found)
+    }
+
+    async fn add_or_update_action(&self, action: &Action) -> Result<(), WakeBotDbError> {
+        self.actions
+            .lock()
+            .unwrap()
+            .insert(action.scope.key(&action.name), action.clone());
+        Ok(())
+    }
+
+    async fn delete_action(
+        &self,
+        scope: &ActionScope,
+        action_name: &str,
+    ) -> Result<(), WakeBotDbError> {
+        self.actions.lock().unwrap().remove(&scope.key(action_name));
+        Ok(())
+    }
+
+    async fn list_actions(
+        &self,
+        guild: &str,
+        user_id: &str,
+    ) -> Result<Vec<Action>, WakeBotDbError> {
+        Ok(self
+            .actions
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|action| match &action.scope {
+                ActionScope::Server {
+                    guild: action_guild,
+                } => action_guild == guild,
+                ActionScope::Personal {
+                    guild: action_guild,
+                    user_id: owner,
+                } => action_guild == guild && owner == user_id,
+                ActionScope::Global => true,
+            })
+            .cloned()
+            .collect())
+    }
+
+    async fn increment_counter(&self, name: &str) -> Result<i32, WakeBotDbError> {
+        let mut counters = self.counters.lock().unwrap();
+        let count = counters.entry(String::from(name)).or_default();
+        *count += 1;
+        Ok(*count)
+    }
+
+    async fn get_crit_profile(&self, scope: &str) -> Result<Option<String>, WakeBotDbError> {
+        Ok(self.crit_profiles.lock().unwrap().get(scope).cloned())
+    }
+
+    async fn set_crit_profile(&self, scope: &str, profile: &str) -> Result<(), WakeBotDbError> {
+        self.crit_profiles
+            .lock()
+            .unwrap()
+            .insert(String::from(scope), String::from(profile));
+        Ok(())
+    }
+
+    async fn add_roll_record(&self, record: &RollRecord) -> Result<(), WakeBotDbError> {
+        let mut roll_records = self.roll_records.lock().unwrap();
+        if roll_records.contains_key(&record.id) {
+            return Err(WakeBotDbError::AlreadyExists);
+        }
+        roll_records.insert(record.id.clone(), record.clone());
+        Ok(())
+    }
+
+    async fn get_roll_record(&self, id: &str) -> Result<RollRecord, WakeBotDbError> {
+        self.roll_records
+            .lock()
+            .unwrap()
+            .get(id)
+            .cloned()
+            .ok_or_else(roll_not_found)
+    }
+
+    async fn get_variable(
+        &self,
+        guild: &str,
+        user_id: &str,
+        name: &str,
+    ) -> Result<Option<String>, WakeBotDbError> {
+        Ok(self
+            .variables
+            .lock()
+            .unwrap()
+            .get(&(String::from(guild), String::from(user_id)))
+            .and_then(|variables| variables.get(name))
+            .cloned())
+    }
+
+    async fn set_variable(
+        &self,
+        guild: &str,
+        user_id: &str,
+        name: &str,
+        value: &str,
+    ) -> Result<(), WakeBotDbError> {
+        self.variables
+            .lock()
+            .unwrap()
+            .entry((String::from(guild), String::from(user_id)))
+            .or_default()
+            .insert(String::from(name), String::from(value));
+        Ok(())
+    }
+
+    async fn delete_variable(
+        &self,
+        guild: &str,
+        user_id: &str,
+        name: &str,
+    ) -> Result<(), WakeBotDbError> {
+        if let Some(variables) = self
+            .variables
+            .lock()
+            .unwrap()
+            .get_mut(&(String::from(guild), String::from(user_id)))
+        {
+            variables.remove(name);
+        }
+        Ok(())
+    }
+
+    async fn list_variables(
+        &self,
+        guild: &str,
+        user_id: &str,
+    ) -> Result<Vec<(String, String)>, WakeBotDbError> {
+        Ok(self
+            .variables
+            .lock()
+            .unwrap()
+            .get(&(String::from(guild), String::from(user_id)))
+            .map(|variables| variables.clone().into_iter().collect())
+            .unwrap_or_default())
+    }
+
+    async fn get_luck_stats(&self, scope: &str) -> Result<LuckStats, WakeBotDbError> {
+        Ok(self
+            .luck_stats
+            .lock()
+            .unwrap()
+            .get(scope)
+            .map(|(_, stats)| stats.clone())
+            .unwrap_or_default())
+    }
+
+    async fn add_luck_stats(
+        &self,
+        scope: &str,
+        user_name: &str,
+        stats: &LuckStats,
+    ) -> Result<(), WakeBotDbError> {
+        let mut luck_stats = self.luck_stats.lock().unwrap();
+        let user = luck_stats.entry(String::from(scope)).or_default();
+        user.0 = String::from(user_name);
+        user.1.merge(stats);
+        Ok(())
+    }
+
+    async fn get_all_luck_stats(
+        &self,
+        prefix: &str,
+    ) -> Result<Vec<(String, LuckStats)>, WakeBotDbError> {
+        Ok(self
+            .luck_stats
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(scope, _)| scope.starts_with(prefix))
+            .map(|(_, user)| user.clone())
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::tests;
+
+    #[tokio::test]
+    async fn actions_follow_scope_precedence() {
+        tests::actions_follow_scope_precedence(&MemoryStorage::default()).await;
+    }
+
+    #[tokio::test]
+    async fn counters_count_up() {
+        tests::counters_count_up(&MemoryStorage::default()).await;
+    }
+
+    #[tokio::test]
+    async fn crit_profiles_are_replaced() {
+        tests::crit_profiles_are_replaced(&MemoryStorage::default()).await;
+    }
+
+    #[tokio::test]
+    async fn roll_records_are_never_overwritten() {
+        tests::roll_records_are_never_overwritten(&MemoryStorage::default()).await;
+    }
+
+    #[tokio::test]
+    async fn luck_stats_add_up() {
+        tests::luck_stats_add_up(&MemoryStorage::default()).await;
+    }
+
+    #[tokio::test]
+    async fn variables_belong_to_one_user() {
+        tests::variables_belong_to_one_user(&MemoryStorage::default()).await;
+    }
+}
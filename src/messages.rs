@@ -0,0 +1,164 @@
+use serenity::model::channel::{AttachmentType, Message};
+use serenity::model::user::User;
+use serenity::prelude::*;
+use std::borrow::Cow;
+
+// Discord's limit for a single message
+pub const MAX_MESSAGE_LENGTH: usize = 2000;
+// Anything that would take more messages than this is sent as a file instead
+const MAX_SPLIT_MESSAGES: usize = 3;
+
+// Breaks text into pieces that fit in a message, splitting between lines where possible
+pub fn split_message(text: &str) -> Vec<String> {
+    let mut messages = vec![];
+    let mut current = String::new();
+    for line in text.lines() {
+        let mut line = line;
+        // A single line that is too long gets cut wherever it hits the limit
+        while line.chars().count() > MAX_MESSAGE_LENGTH {
+            if !current.is_empty() {
+                messages.push(std::mem::take(&mut current));
+            }
+            let split_at = line
+                .char_indices()
+                .nth(MAX_MESSAGE_LENGTH)
+                .map_or(line.len(), |(i, _)| i);
+            messages.push(String::from(&line[..split_at]));
+            line = &line[split_at..];
+        }
+        if !current.is_empty()
+            && current.chars().count() + line.chars().count() + 1 > MAX_MESSAGE_LENGTH
+        {
+            messages.push(std::mem::take(&mut current));
+        }
+        if !current.is_empty() {
+            current.push('\n');
+        }
+        current += line;
+    }
+    if !current.is_empty() || messages.is_empty() {
+        messages.push(current);
+    }
+    messages
+}
+
+fn needs_attachment(messages: &[String]) -> bool {
+    messages.len() > MAX_SPLIT_MESSAGES
+}
+
+// Replies with text of any length, splitting it over a few messages or attaching it as a file if it's
+// very long. Failures are logged rather than bringing down the handler.
+pub async fn reply(ctx: &Context, msg: &Message, text: impl AsRef<str>) {
+    let text = text.as_ref();
+    let messages = split_message(text);
+    if needs_attachment(&messages) {
+        let result = msg
+            .channel_id
+            .send_message(&ctx.http, |m| {
+                m.reference_message(msg)
+                    .content("That's too long for Discord, so the full result is attached.")
+                    .add_file(AttachmentType::Bytes {
+                        data: Cow::Owned(text.as_bytes().to_vec()),
+                        filename: String::from("result.txt"),
+                    })
+            })
+            .await;
+        if let Err(e) = result {
+            println!("There was a problem sending reply: {}", e);
+        }
+        return;
+    }
+    for (i, message) in messages.into_iter().enumerate() {
+        let result = if i == 0 {
+            msg.reply(&ctx.http, message).await
+        } else {
+            msg.channel_id.say(&ctx.http, message).await
+        };
+        if let Err(e) = result {
+            println!("There was a problem sending reply: {}", e);
+            return;
+        }
+    }
+}
+
+pub async fn direct_message(ctx: &Context, user: &User, text: impl AsRef<str>) {
+    let text = text.as_ref();
+    let messages = split_message(text);
+    if needs_attachment(&messages) {
+        let result = user
+            .direct_message(&ctx.http, |m| {
+                m.content("That's too long for Discord, so the full result is attached.")
+                    .add_file(AttachmentType::Bytes {
+                        data: Cow::Owned(text.as_bytes().to_vec()),
+                        filename: String::from("result.txt"),
+                    })
+            })
+            .await;
+        if let Err(e) = result {
+            println!("There was a problem sending direct message: {}", e);
+        }
+        return;
+    }
+    for message in messages {
+        if let Err(e) = user.direct_message(&ctx.http, |m| m.content(message)).await {
+            println!("There was a problem sending direct message: {}", e);
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lengths(messages: &[String]) -> Vec<usize> {
+        messages
+            .iter()
+            .map(|message| message.chars().count())
+            .collect()
+    }
+
+    #[test]
+    fn cuts_long_lines_at_the_limit() {
+        assert_eq!(
+            lengths(&split_message(&"a".repeat(4500))),
+            [2000, 2000, 500]
+        );
+        assert_eq!(split_message(""), [""]);
+    }
+
+    #[test]
+    fn splits_between_lines() {
+        let (a, b, c) = ("a".repeat(1000), "b".repeat(999), "c".repeat(10));
+        assert_eq!(
+            split_message(&format!("{}\n{}\n{}", a, b, c)),
+            [format!("{}\n{}", a, b), c.clone()]
+        );
+        let b = "b".repeat(1000);
+        assert_eq!(
+            split_message(&format!("{}\n{}\n{}", a, b, c)),
+            [a.clone(), format!("{}\n{}", b, c)]
+        );
+        // What's left of a cut line carries on in the same message as the lines after it
+        assert_eq!(
+            split_message(&format!("{}\n{}", "a".repeat(2100), c)),
+            ["a".repeat(2000), format!("{}\n{}", "a".repeat(100), c)]
+        );
+    }
+
+    #[test]
+    fn counts_characters_rather_than_bytes() {
+        let messages = split_message(&"é".repeat(2500));
+        assert_eq!(lengths(&messages), [2000, 500]);
+        let messages = split_message(&format!("{}\n{}", "🎲".repeat(1500), "🎲".repeat(499)));
+        assert_eq!(lengths(&messages), [2000]);
+    }
+
+    #[test]
+    fn attaches_anything_over_three_messages() {
+        assert!(!needs_attachment(&split_message(&"a".repeat(6000))));
+        assert!(needs_attachment(&split_message(&"a".repeat(6001))));
+        let lines = vec!["a".repeat(1500); 4].join("\n");
+        assert!(needs_attachment(&split_message(&lines)));
+    }
+}
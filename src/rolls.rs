@@ -1,60 +1,59 @@
-use crate::errors::WakeBotError;
-use fancy_regex::Regex;
-use rand::Rng;
-use shunting::{MathContext, ShuntingParser};
-use std::fmt::Debug;
+use crate::errors::{format_error, RollErrorKind, WakeBotError};
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use std::collections::BTreeMap;
+use std::fmt::{self, Debug};
+use std::ops::Range;
 
-// Anything between parens, we are going to attempt to feed into the function. Panic and respond with an error if it isn't properly formatted
-// How to handle nested parens? I guess if this is the first step, to "step in" to the paren string, then we'd just step into whatever is in the parens infinitely
-const PAREN_REGEX: &str = r"\((.+)\)";
-
-// Isolate the rolls, so we can convert them into numbers and replace them in the string
-const ROLL_REGEX: &str =
-    r"((\d*)d(\d+)((k|kh|kl)(\d+))?)(( ?[+*/-] ?(\d+(?!\.)|(\d*\.\d+))(?!d))*)";
-pub const DICE_COMMAND_REGEX: &str = r"!\d*d\d+((k|kh|kl)\d+)?";
+// Quick check used by the message handler to decide whether a message is a roll. The expression itself is
+// validated by the parser below, this only needs to find a dice term preceded by plain arithmetic.
+pub const DICE_COMMAND_REGEX: &str = r"^!(\d+\s*[x#]\s*)?[\d\s().+*/-]*d(\d+|[fF]|\{)";
 
 const MAX_QUANTITY: usize = 1000;
+const MAX_REPEATS: usize = 20;
+// Past this many dice, a roll shows how often each face came up instead of listing every die. The roll log
+// always has every die.
+const MAX_LISTED_DICE: usize = 50;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct RollResult {
     pub original_text: String,
     pub non_roll_portion: String,
+    // Which face each die landed on, counting from 1. For numbered dice this is also the value of the die.
     pub rolls: Vec<i32>,
-    pub roll_total: u32,
-    // Critical success and failure detection is determined solely by coming up with 20 or 1 on a d20 roll
+    // Sum of the kept dice, or the number of successes when the term is a success pool. Wider than the
+    // dice themselves, since a thousand of the biggest dice would overflow an i32.
+    pub roll_total: i64,
+    // Critical success and failure detection uses the term's own cs/cf targets if given, otherwise the
+    // crit profile the roll was made with. Dice that were dropped or rerolled never count.
     pub has_critical_success: bool,
     pub has_critical_failure: bool,
-    sorting_priority: usize,
-}
-
-impl RollResult {
-    pub fn new(
-        original_text: String,
-        non_roll_portion: String,
-        rolls: Vec<i32>,
-        roll_total: u32,
-        has_critical_success: bool,
-        has_critical_failure: bool,
-        sorting_priority: usize,
-    ) -> Self {
-        RollResult {
-            original_text,
-            non_roll_portion,
-            rolls,
-            roll_total,
-            has_critical_success,
-            has_critical_failure,
-            // Sort by location found in string to order from left to right, not be evaluation order
-            sorting_priority,
-        }
-    }
+    // Only set for success pools. A botch is no successes with at least one failure, a glitch is more than
+    // half of the dice coming up as failures. Without a failure target, 1s count as failures.
+    pub has_botch: bool,
+    pub has_glitch: bool,
+    // Parallel to rolls, records what happened to each die beyond its face
+    pub marks: Vec<DieMark>,
+    pub term: DiceTerm,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DieMark {
+    Normal,
+    // Triggered another die through an explosion
+    Exploded,
+    // Discarded by a reroll modifier, the following die replaces it
+    Rerolled,
+}
+
+#[derive(Debug, Clone)]
 pub struct RollStringResult<'a> {
     pub original_text: &'a str,
     pub converted_text: String,
     pub rolls: Vec<RollResult>,
+    pub total: f64,
+    // Comment given after '#', shown as a heading for the roll
+    pub label: Option<String>,
 }
 
 impl<'a> RollStringResult<'a> {
@@ -63,205 +62,1990 @@ impl<'a> RollStringResult<'a> {
             converted_text: String::from(original_text),
             original_text,
             rolls: vec![],
+            total: 0.0,
+            label: None,
         }
     }
 }
 
-// This accepts a roll string, which is a certain amount of numbers or rolls all separated by operators
-pub fn interpret_rolls<'a>(
-    input: &'a str,
-    input_offset: usize, // This is called recursively, keep track of where exactly we are evaluating in the string
-) -> Result<RollStringResult, WakeBotError> {
-    // Remove ! from beginning if it is there (legacy behavior from previously saved actions in AWS)
-    let input = if input.starts_with("!") {
-        &input[1..]
-    } else {
-        input
-    };
-    let mut result = RollStringResult::new(input);
-    // We will have checked that it is a valid input string before invoking
-
-    // Check for parens and recursively invoke as needed.
-    let paren_regex = Regex::new(PAREN_REGEX).unwrap();
-
-    // Target any parts of the string nested in params and replace them by recursively calling this function
-    loop {
-        let text_result: String;
-        let start: usize;
-        let end: usize;
-        match paren_regex.captures(&result.converted_text) {
-            Ok(Some(cap)) => {
-                let nested_string = cap.get(1).unwrap();
-                start = nested_string.start();
-                end = nested_string.end();
-                let mut nested_result = interpret_rolls(nested_string.as_str(), start).unwrap();
-                text_result = nested_result.converted_text;
-                result.rolls.append(&mut nested_result.rolls);
-            }
-            Ok(None) => break,
-            Err(_) => {
-                return Err(WakeBotError::new(
-                    "Error occurred while parsing paren regex",
+#[derive(Debug, Clone, PartialEq)]
+enum TokenKind {
+    Integer(u32),
+    Decimal(f64),
+    // Letters are kept separate so modifiers can follow each other directly, as in "4d6!pk3"
+    Letter(char),
+    Plus,
+    Minus,
+    Star,
+    Slash,
+    LeftParen,
+    RightParen,
+    LeftBrace,
+    RightBrace,
+    Comma,
+    // Bracketed tag such as "[fire]", holding the text between the brackets
+    Tag(String),
+    Bang,
+    Greater,
+    GreaterEqual,
+    Less,
+    LessEqual,
+    Equal,
+}
+
+#[derive(Debug, Clone)]
+struct Token {
+    kind: TokenKind,
+    start: usize,
+    end: usize,
+}
+
+fn tokenize(input: &str) -> Result<Vec<Token>, WakeBotError> {
+    let mut tokens = vec![];
+    let chars = input.char_indices().collect::<Vec<(usize, char)>>();
+    let mut i = 0;
+    while i < chars.len() {
+        let (start, c) = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+            continue;
+        }
+        if c == '[' {
+            let length = input[start..].find(']').ok_or_else(|| {
+                WakeBotError::roll(RollErrorKind::Unclosed("bracket for tag"), start..start + 1)
+            })?;
+            let end = start + length + 1;
+            tokens.push(Token {
+                kind: TokenKind::Tag(String::from(input[start + 1..end - 1].trim())),
+                start,
+                end,
+            });
+            while i < chars.len() && chars[i].0 < end {
+                i += 1;
+            }
+            continue;
+        }
+        if c.is_ascii_digit() || c == '.' {
+            let mut j = i;
+            while j < chars.len() && (chars[j].1.is_ascii_digit() || chars[j].1 == '.') {
+                j += 1;
+            }
+            let end = chars.get(j).map_or(input.len(), |(n, _)| *n);
+            let text = &input[start..end];
+            let kind = if text.contains('.') {
+                TokenKind::Decimal(text.parse::<f64>().map_err(|_| {
+                    WakeBotError::roll(RollErrorKind::InvalidNumber(String::from(text)), start..end)
+                })?)
+            } else {
+                TokenKind::Integer(text.parse::<u32>().map_err(|_| {
+                    WakeBotError::roll(
+                        RollErrorKind::NumberTooLarge(String::from(text)),
+                        start..end,
+                    )
+                })?)
+            };
+            tokens.push(Token { kind, start, end });
+            i = j;
+            continue;
+        }
+        if c.is_ascii_alphabetic() {
+            tokens.push(Token {
+                kind: TokenKind::Letter(c),
+                start,
+                end: start + 1,
+            });
+            i += 1;
+            continue;
+        }
+        let next_is_equal = matches!(chars.get(i + 1), Some((_, '=')));
+        let kind = match c {
+            '>' if next_is_equal => TokenKind::GreaterEqual,
+            '<' if next_is_equal => TokenKind::LessEqual,
+            '>' => TokenKind::Greater,
+            '<' => TokenKind::Less,
+            '=' => TokenKind::Equal,
+            '!' => TokenKind::Bang,
+            '+' => TokenKind::Plus,
+            '-' => TokenKind::Minus,
+            '*' => TokenKind::Star,
+            '/' => TokenKind::Slash,
+            '(' => TokenKind::LeftParen,
+            ')' => TokenKind::RightParen,
+            '{' => TokenKind::LeftBrace,
+            '}' => TokenKind::RightBrace,
+            ',' => TokenKind::Comma,
+            _ => {
+                return Err(WakeBotError::roll(
+                    RollErrorKind::UnexpectedCharacter(c),
+                    start..start + c.len_utf8(),
                 ))
             }
+        };
+        let length = if matches!(kind, TokenKind::GreaterEqual | TokenKind::LessEqual) {
+            2
+        } else {
+            1
+        };
+        tokens.push(Token {
+            kind,
+            start,
+            end: start + length,
+        });
+        i += length;
+    }
+    Ok(tokens)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum KeepKind {
+    Highest,
+    Lowest,
+    DropHighest,
+    DropLowest,
+}
+
+// Comparisons follow the usual dice roller convention, where '>' and '<' include the number itself
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CompareOp {
+    AtLeast,
+    AtMost,
+    Exactly,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ComparePoint {
+    pub op: CompareOp,
+    pub value: i32,
+}
+
+impl ComparePoint {
+    // Takes single dice as well as the wider totals of compounded dice
+    pub fn matches(&self, n: impl Into<i64>) -> bool {
+        let (n, value) = (n.into(), i64::from(self.value));
+        match self.op {
+            CompareOp::AtLeast => n >= value,
+            CompareOp::AtMost => n <= value,
+            CompareOp::Exactly => n == value,
         }
-        result.converted_text = String::from(&result.converted_text[0..start - 1])
-            + &text_result
-            + &result.converted_text[end + 1..];
     }
+}
 
-    let roll_regex = Regex::new(ROLL_REGEX).unwrap();
+#[derive(Debug, Clone, PartialEq)]
+pub enum Faces {
+    Numbered(u32),
+    // Fate/Fudge dice, with an equal chance of -1, 0 or +1
+    Fate,
+    Custom(Vec<i32>),
+}
 
-    loop {
-        // Invoke function that handles rolling
-        match roll_regex.captures(&result.converted_text) {
-            Ok(Some(cap)) => {
-                let dice_count = cap.get(2).unwrap();
-                let dice_count = if let Ok(n) = dice_count.as_str().parse::<usize>() {
-                    n
-                } else {
-                    1
-                };
-                if dice_count > MAX_QUANTITY {
-                    return Err(WakeBotError::new(&format!(
-                        "Max number of dice is {}",
-                        MAX_QUANTITY
-                    )));
-                }
-                let dice_max = cap
-                    .get(3)
-                    .unwrap()
-                    .as_str()
-                    .parse::<usize>()
-                    .expect("Error while parsing dice max.");
-                let mut results = vec![];
-                for _ in 0..dice_count {
-                    let roll_result: i32 = rand::thread_rng()
-                        .gen_range(1..=dice_max)
-                        .try_into()
-                        .expect("Negative value generated by roll");
-                    results.push(roll_result);
+impl Faces {
+    pub fn count(&self) -> u32 {
+        match self {
+            Faces::Numbered(sides) => *sides,
+            Faces::Fate => 3,
+            Faces::Custom(values) => values.len() as u32,
+        }
+    }
+
+    // Value of the face a die landed on, counting from 1
+    pub fn value(&self, face: i32) -> i32 {
+        match self {
+            Faces::Numbered(_) => face,
+            Faces::Fate => face - 2,
+            Faces::Custom(values) => values[face as usize - 1],
+        }
+    }
+
+    fn max_value(&self) -> i32 {
+        match self {
+            Faces::Numbered(sides) => *sides as i32,
+            Faces::Fate => 1,
+            Faces::Custom(values) => values.iter().copied().max().unwrap_or(0),
+        }
+    }
+
+    fn min_value(&self) -> i32 {
+        match self {
+            Faces::Numbered(_) => 1,
+            Faces::Fate => -1,
+            Faces::Custom(values) => values.iter().copied().min().unwrap_or(0),
+        }
+    }
+
+    fn all_match(&self, point: &ComparePoint) -> bool {
+        match self {
+            // Avoid walking every face of a huge die
+            Faces::Numbered(sides) => match point.op {
+                CompareOp::AtLeast => point.value <= 1,
+                CompareOp::AtMost => point.value >= *sides as i32,
+                CompareOp::Exactly => *sides == 1 && point.value == 1,
+            },
+            _ => (1..=self.count() as i32).all(|face| point.matches(self.value(face))),
+        }
+    }
+
+    // How a die showing this value is displayed
+    fn symbol(&self, value: i32) -> String {
+        match (self, value) {
+            (Faces::Fate, 1) => String::from("+"),
+            (Faces::Fate, -1) => String::from("-"),
+            (Faces::Fate, 0) => String::from(" "),
+            _ => value.to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExplodeKind {
+    // Each explosion adds a new die
+    Standard,
+    // Explosions are added onto the die that triggered them, so the chain counts as one die
+    Compound,
+    // Like standard, but every added die is worth one less
+    Penetrate,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Explode {
+    pub kind: ExplodeKind,
+    pub point: ComparePoint,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Reroll {
+    // Only reroll a single time, keeping the new result even if it also matches
+    pub once: bool,
+    pub point: ComparePoint,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SuccessPool {
+    pub success: ComparePoint,
+    pub failure: Option<ComparePoint>,
+    // Successes matching this count twice
+    pub double: Option<ComparePoint>,
+}
+
+impl SuccessPool {
+    // How much a single die adds to the success count
+    pub fn score(&self, value: i64) -> i32 {
+        if self.success.matches(value) {
+            if self.double.is_some_and(|d| d.matches(value)) {
+                2
+            } else {
+                1
+            }
+        } else if self.is_failure(value) {
+            -1
+        } else {
+            0
+        }
+    }
+
+    fn is_failure(&self, value: i64) -> bool {
+        self.failure.is_some_and(|f| f.matches(value))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DiceTerm {
+    pub count: usize,
+    pub faces: Faces,
+    pub keep: Option<(KeepKind, usize)>,
+    pub explode: Option<Explode>,
+    pub reroll: Option<Reroll>,
+    pub pool: Option<SuccessPool>,
+    pub crit_success: Option<ComparePoint>,
+    pub crit_failure: Option<ComparePoint>,
+    // Damage type or similar given in brackets after the term, e.g. "fire" in "1d8[fire]"
+    pub tag: Option<String>,
+    // Source text of the dice term itself, e.g. "4d6kh3"
+    pub text: String,
+    // Where the term was found in the roll string
+    pub span: Range<usize>,
+    // Constant arithmetic directly following the term (e.g. "+5" in "1d20+5"), shown next to the roll
+    pub trailing: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BinaryOp {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+}
+
+#[derive(Debug, Clone)]
+pub enum Expr {
+    Number(f64),
+    Dice(DiceTerm),
+    Negate(Box<Expr>),
+    // The span is the operator's, for errors like dividing by zero
+    Binary(BinaryOp, Box<Expr>, Box<Expr>, Range<usize>),
+}
+
+struct Parser<'a> {
+    input: &'a str,
+    tokens: Vec<Token>,
+    position: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<&TokenKind> {
+        self.tokens.get(self.position).map(|t| &t.kind)
+    }
+
+    fn peek_at(&self, offset: usize) -> Option<&TokenKind> {
+        self.tokens.get(self.position + offset).map(|t| &t.kind)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        if token.is_some() {
+            self.position += 1;
+        }
+        token
+    }
+
+    // Consumes the next token if it is the given letter
+    fn eat_letter(&mut self, letter: char) -> bool {
+        if self.peek() == Some(&TokenKind::Letter(letter)) {
+            self.position += 1;
+            return true;
+        }
+        false
+    }
+
+    // Span of the token at the given position, or the very end of the input if there isn't one
+    fn span_at(&self, position: usize) -> Range<usize> {
+        match self.tokens.get(position) {
+            Some(token) => token.start..token.end,
+            None => self.input.len()..self.input.len(),
+        }
+    }
+
+    // Span from the token at the given position up to the last one consumed
+    fn span_from(&self, position: usize) -> Range<usize> {
+        self.tokens[position].start..self.tokens[self.position - 1].end
+    }
+
+    fn expect_integer(&mut self, what: &str) -> Result<u32, WakeBotError> {
+        let span = self.span_at(self.position);
+        match self.next() {
+            Some(Token {
+                kind: TokenKind::Integer(n),
+                ..
+            }) => Ok(n),
+            _ => Err(WakeBotError::roll(
+                RollErrorKind::Expected(String::from(what)),
+                span,
+            )),
+        }
+    }
+
+    // expression := product (('+' | '-') product)*
+    fn parse_expression(&mut self) -> Result<Expr, WakeBotError> {
+        let mut left = self.parse_product()?;
+        loop {
+            let op = match self.peek() {
+                Some(TokenKind::Plus) => BinaryOp::Add,
+                Some(TokenKind::Minus) => BinaryOp::Subtract,
+                _ => return Ok(left),
+            };
+            let span = self.span_at(self.position);
+            self.position += 1;
+            let right = self.parse_product()?;
+            left = Expr::Binary(op, Box::new(left), Box::new(right), span);
+        }
+    }
+
+    // product := unary (('*' | '/') unary)*
+    fn parse_product(&mut self) -> Result<Expr, WakeBotError> {
+        let mut left = self.parse_unary()?;
+        loop {
+            let op = match self.peek() {
+                Some(TokenKind::Star) => BinaryOp::Multiply,
+                Some(TokenKind::Slash) => BinaryOp::Divide,
+                _ => return Ok(left),
+            };
+            let span = self.span_at(self.position);
+            self.position += 1;
+            let right = self.parse_unary()?;
+            left = Expr::Binary(op, Box::new(left), Box::new(right), span);
+        }
+    }
+
+    // unary := ('-' | '+') unary | atom
+    fn parse_unary(&mut self) -> Result<Expr, WakeBotError> {
+        match self.peek() {
+            Some(TokenKind::Minus) => {
+                self.position += 1;
+                Ok(Expr::Negate(Box::new(self.parse_unary()?)))
+            }
+            Some(TokenKind::Plus) => {
+                self.position += 1;
+                self.parse_unary()
+            }
+            _ => self.parse_atom(),
+        }
+    }
+
+    // atom := '(' expression ')' | dice | number
+    fn parse_atom(&mut self) -> Result<Expr, WakeBotError> {
+        if self.is_dice_start() {
+            return Ok(Expr::Dice(self.parse_dice()?));
+        }
+        let span = self.span_at(self.position);
+        match self.next() {
+            Some(Token {
+                kind: TokenKind::LeftParen,
+                ..
+            }) => {
+                let inner = self.parse_expression()?;
+                match self.next() {
+                    Some(Token {
+                        kind: TokenKind::RightParen,
+                        ..
+                    }) => Ok(inner),
+                    _ => Err(WakeBotError::roll(
+                        RollErrorKind::Unclosed("parenthesis"),
+                        span,
+                    )),
                 }
-                let keep_str = cap.get(5);
-                let keep_count = cap.get(6);
-                let results_clone = results.clone();
-                let mut removed_indices = results_clone
-                    .iter()
-                    .enumerate()
-                    .collect::<Vec<(usize, &i32)>>();
-                removed_indices.sort_unstable_by(|a, b| a.1.cmp(b.1));
-                if let Some(str) = keep_str {
-                    let count = keep_count
-                        .expect("Keep string passed with no keep count.")
-                        .as_str()
-                        .parse::<i32>()
-                        .expect("Invalid keep count passed.");
-                    // results length limited by MAX_QUANTITY
-                    let mut number_to_remove: i32 = (results.len() as i32) - count;
-                    if number_to_remove < 0 {
-                        number_to_remove = 0;
+            }
+            Some(Token {
+                kind: TokenKind::Integer(n),
+                ..
+            }) => Ok(Expr::Number(n as f64)),
+            Some(Token {
+                kind: TokenKind::Decimal(n),
+                ..
+            }) => Ok(Expr::Number(n)),
+            Some(token) => Err(WakeBotError::roll(
+                RollErrorKind::UnexpectedToken(String::from(&self.input[token.start..token.end])),
+                span,
+            )),
+            None => Err(WakeBotError::roll(RollErrorKind::UnexpectedEnd, span)),
+        }
+    }
+
+    fn is_dice_start(&self) -> bool {
+        let is_die = |kind: Option<&TokenKind>| kind == Some(&TokenKind::Letter('d'));
+        match self.peek() {
+            Some(TokenKind::Integer(_)) => is_die(self.peek_at(1)),
+            other => is_die(other),
+        }
+    }
+
+    // compare_point := ('>' | '>=' | '<' | '<=' | '=') integer
+    fn parse_compare_point(&mut self) -> Result<Option<ComparePoint>, WakeBotError> {
+        let op = match self.peek() {
+            Some(TokenKind::Greater | TokenKind::GreaterEqual) => CompareOp::AtLeast,
+            Some(TokenKind::Less | TokenKind::LessEqual) => CompareOp::AtMost,
+            Some(TokenKind::Equal) => CompareOp::Exactly,
+            _ => return Ok(None),
+        };
+        self.position += 1;
+        let value = self.expect_integer("a number to compare against")? as i32;
+        Ok(Some(ComparePoint { op, value }))
+    }
+
+    // target := compare_point | integer
+    fn parse_target(&mut self, what: &str) -> Result<ComparePoint, WakeBotError> {
+        if let Some(point) = self.parse_compare_point()? {
+            return Ok(point);
+        }
+        let value = self.expect_integer(what)? as i32;
+        Ok(ComparePoint {
+            op: CompareOp::Exactly,
+            value,
+        })
+    }
+
+    // reroll := ('r' | 'ro') target
+    fn parse_reroll(&mut self, faces: &Faces) -> Result<Reroll, WakeBotError> {
+        let start = self.position;
+        // Skip the 'r' itself, the caller has already checked it
+        self.position += 1;
+        let once = self.eat_letter('o');
+        let point = self.parse_target("a number to reroll on")?;
+        if !once && faces.all_match(&point) {
+            return Err(WakeBotError::roll(
+                RollErrorKind::AlwaysRerolls,
+                self.span_from(start),
+            ));
+        }
+        Ok(Reroll { once, point })
+    }
+
+    // explode := '!' ['!' | 'p'] [compare_point]
+    fn parse_explode(&mut self, faces: &Faces) -> Result<Explode, WakeBotError> {
+        let start = self.position;
+        // Skip the '!' itself, the caller has already checked it
+        self.position += 1;
+        let kind = match self.peek() {
+            Some(TokenKind::Bang) => ExplodeKind::Compound,
+            Some(TokenKind::Letter('p')) => ExplodeKind::Penetrate,
+            _ => ExplodeKind::Standard,
+        };
+        if kind != ExplodeKind::Standard {
+            self.position += 1;
+        }
+        let point = self.parse_compare_point()?.unwrap_or(ComparePoint {
+            op: CompareOp::AtLeast,
+            value: faces.max_value(),
+        });
+        if faces.all_match(&point) {
+            return Err(WakeBotError::roll(
+                RollErrorKind::AlwaysExplodes,
+                self.span_from(start),
+            ));
+        }
+        Ok(Explode { kind, point })
+    }
+
+    // faces := integer | 'F' | '{' ['-'] integer (',' ['-'] integer)* '}'
+    fn parse_faces(&mut self) -> Result<Faces, WakeBotError> {
+        match self.peek() {
+            Some(TokenKind::Letter('F' | 'f')) => {
+                self.position += 1;
+                Ok(Faces::Fate)
+            }
+            Some(TokenKind::LeftBrace) => {
+                let brace_span = self.span_at(self.position);
+                self.position += 1;
+                let mut values = vec![];
+                loop {
+                    let negative = self.peek() == Some(&TokenKind::Minus);
+                    if negative {
+                        self.position += 1;
                     }
-                    if str.as_str().eq("kl") {
-                        removed_indices.reverse();
+                    let value = self.expect_integer("a number for each face")?;
+                    if value > i32::MAX as u32 {
+                        return Err(WakeBotError::roll(
+                            RollErrorKind::FaceTooLarge(value),
+                            self.span_at(self.position - 1),
+                        ));
                     }
-                    removed_indices.drain(number_to_remove as usize..removed_indices.len()); // Above condition ensures non-negative
-                    for (i, _) in removed_indices {
-                        results[i] = -results[i];
+                    values.push(if negative {
+                        -(value as i32)
+                    } else {
+                        value as i32
+                    });
+                    match self.next() {
+                        Some(Token {
+                            kind: TokenKind::Comma,
+                            ..
+                        }) => continue,
+                        Some(Token {
+                            kind: TokenKind::RightBrace,
+                            ..
+                        }) => break,
+                        _ => {
+                            return Err(WakeBotError::roll(
+                                RollErrorKind::Unclosed("brace for faces"),
+                                brace_span,
+                            ))
+                        }
                     }
                 }
-                let has_critical_success = dice_max == 20 && results.iter().any(|n| *n == 20);
-                let has_critical_failure = dice_max == 20 && results.iter().any(|n| *n == 1);
-                let roll_total = results.iter().fold(0, |mut a, b| {
-                    let n = *b;
-                    if n >= 0 {
-                        a += n as u32;
+                Ok(Faces::Custom(values))
+            }
+            _ => {
+                let sides = self.expect_integer("a die size after 'd'")?;
+                if sides == 0 || sides > i32::MAX as u32 {
+                    return Err(WakeBotError::roll(
+                        RollErrorKind::InvalidDieSize(sides),
+                        self.span_at(self.position - 1),
+                    ));
+                }
+                Ok(Faces::Numbered(sides))
+            }
+        }
+    }
+
+    // dice := [integer] 'd' faces modifier* [tag]
+    // modifier := ('k' | 'kh' | 'kl' | 'd' | 'dh' | 'dl') integer | explode | reroll | compare_point
+    //     | 'f' target | 'ds' target | 'cs' target | 'cf' target
+    fn parse_dice(&mut self) -> Result<DiceTerm, WakeBotError> {
+        let start_position = self.position;
+        let start = self.tokens[self.position].start;
+        let count = match self.peek() {
+            Some(TokenKind::Integer(n)) => {
+                let n = *n as usize;
+                self.position += 1;
+                n
+            }
+            _ => 1,
+        };
+        if count > MAX_QUANTITY {
+            return Err(WakeBotError::roll(
+                RollErrorKind::TooManyDice(MAX_QUANTITY),
+                self.span_at(start_position),
+            ));
+        }
+        // Skip the 'd' itself, is_dice_start has already checked it
+        self.position += 1;
+        let faces = self.parse_faces()?;
+        let mut keep = None;
+        let mut explode = None;
+        let mut reroll = None;
+        let mut success = None;
+        let mut failure = None;
+        let mut double = None;
+        let mut crit_success = None;
+        let mut crit_failure = None;
+        loop {
+            match self.peek() {
+                Some(TokenKind::Letter('k')) if keep.is_none() => {
+                    let modifier_start = self.position;
+                    self.position += 1;
+                    let kind = if self.eat_letter('l') {
+                        KeepKind::Lowest
+                    } else {
+                        self.eat_letter('h');
+                        KeepKind::Highest
+                    };
+                    let n = self.expect_integer("a keep count")? as usize;
+                    if n > count {
+                        return Err(WakeBotError::roll(
+                            RollErrorKind::KeepTooMany { keep: n, count },
+                            self.span_from(modifier_start),
+                        ));
                     }
-                    a
-                });
-                let dice_str = cap.get(1).unwrap();
-                let (start, end) = (dice_str.start(), dice_str.end());
-                let non_roll_portion = String::from(if let Some(str) = cap.get(7) {
-                    str.as_str()
+                    keep = Some((kind, n));
+                }
+                Some(TokenKind::Letter('d'))
+                    if keep.is_none() && self.peek_at(1) != Some(&TokenKind::Letter('s')) =>
+                {
+                    let modifier_start = self.position;
+                    self.position += 1;
+                    let kind = if self.eat_letter('h') {
+                        KeepKind::DropHighest
+                    } else {
+                        self.eat_letter('l');
+                        KeepKind::DropLowest
+                    };
+                    let n = self.expect_integer("a drop count")? as usize;
+                    if n > count {
+                        return Err(WakeBotError::roll(
+                            RollErrorKind::DropTooMany { drop: n, count },
+                            self.span_from(modifier_start),
+                        ));
+                    }
+                    keep = Some((kind, n));
+                }
+                Some(TokenKind::Bang) if explode.is_none() => {
+                    explode = Some(self.parse_explode(&faces)?);
+                }
+                Some(TokenKind::Letter('r')) if reroll.is_none() => {
+                    reroll = Some(self.parse_reroll(&faces)?);
+                }
+                Some(
+                    TokenKind::Greater
+                    | TokenKind::GreaterEqual
+                    | TokenKind::Less
+                    | TokenKind::LessEqual
+                    | TokenKind::Equal,
+                ) if success.is_none() => {
+                    success = self.parse_compare_point()?;
+                }
+                Some(TokenKind::Letter('f')) if failure.is_none() => {
+                    self.position += 1;
+                    failure = Some(self.parse_target("a number to count as a failure")?);
+                }
+                Some(TokenKind::Letter('c'))
+                    if crit_success.is_none()
+                        && self.peek_at(1) == Some(&TokenKind::Letter('s')) =>
+                {
+                    self.position += 2;
+                    crit_success = Some(self.parse_target("a number to count as a critical")?);
+                }
+                Some(TokenKind::Letter('c'))
+                    if crit_failure.is_none()
+                        && self.peek_at(1) == Some(&TokenKind::Letter('f')) =>
+                {
+                    self.position += 2;
+                    crit_failure =
+                        Some(self.parse_target("a number to count as a critical failure")?);
+                }
+                Some(TokenKind::Letter('d'))
+                    if double.is_none() && self.peek_at(1) == Some(&TokenKind::Letter('s')) =>
+                {
+                    self.position += 2;
+                    double = Some(self.parse_target("a number to count as a double success")?);
+                }
+                _ => break,
+            }
+        }
+        let pool = match success {
+            Some(success) => Some(SuccessPool {
+                success,
+                failure,
+                double,
+            }),
+            None if failure.is_some() || double.is_some() => {
+                return Err(WakeBotError::roll(
+                    RollErrorKind::PoolWithoutSuccess,
+                    self.span_from(start_position),
+                ))
+            }
+            None => None,
+        };
+        let tag = match self.peek() {
+            Some(TokenKind::Tag(tag)) => {
+                let tag = tag.clone();
+                self.position += 1;
+                Some(tag)
+            }
+            _ => None,
+        };
+        let end = self.tokens[self.position - 1].end;
+        Ok(DiceTerm {
+            count,
+            faces,
+            keep,
+            explode,
+            reroll,
+            pool,
+            crit_success,
+            crit_failure,
+            tag,
+            text: String::from(&self.input[start..end]),
+            span: start..end,
+            trailing: self.trailing_arithmetic(end),
+        })
+    }
+
+    // Finds constant arithmetic directly after a dice term, stopping before anything that starts another roll
+    fn trailing_arithmetic(&self, term_end: usize) -> String {
+        let mut offset = 0;
+        let mut end = term_end;
+        while let (Some(op), Some(number)) = (self.peek_at(offset), self.peek_at(offset + 1)) {
+            let is_op = matches!(
+                op,
+                TokenKind::Plus | TokenKind::Minus | TokenKind::Star | TokenKind::Slash
+            );
+            let is_number = matches!(number, TokenKind::Integer(_) | TokenKind::Decimal(_));
+            let starts_dice = self.peek_at(offset + 2) == Some(&TokenKind::Letter('d'));
+            if !is_op || !is_number || starts_dice {
+                break;
+            }
+            end = self.tokens[self.position + offset + 1].end;
+            offset += 2;
+        }
+        String::from(&self.input[term_end..end])
+    }
+}
+
+pub fn parse_rolls(input: &str) -> Result<Expr, WakeBotError> {
+    let tokens = tokenize(input)?;
+    if tokens.is_empty() {
+        return Err(WakeBotError::EmptyRoll);
+    }
+    let mut parser = Parser {
+        input,
+        tokens,
+        position: 0,
+    };
+    let expr = parser.parse_expression()?;
+    if let Some(token) = parser.tokens.get(parser.position) {
+        return Err(WakeBotError::roll(
+            RollErrorKind::UnexpectedToken(String::from(&input[token.start..token.end])),
+            token.start..token.end,
+        ));
+    }
+    Ok(expr)
+}
+
+// Where the dice get their randomness. Live rolls use the OS generator, while tests and replays use a
+// seeded ChaCha so the same seed always gives the same dice.
+pub trait DiceRng {
+    // A number from 1 to sides, inclusive
+    fn roll(&mut self, sides: u32) -> i32;
+}
+
+impl<R: Rng> DiceRng for R {
+    fn roll(&mut self, sides: u32) -> i32 {
+        self.gen_range(1..=sides as i32)
+    }
+}
+
+pub fn seeded_rng(seed: u64) -> ChaCha8Rng {
+    ChaCha8Rng::seed_from_u64(seed)
+}
+
+struct Die {
+    face: i32,
+    value: i32,
+    mark: DieMark,
+}
+
+fn roll_die(
+    rng: &mut dyn DiceRng,
+    term: &DiceTerm,
+    rolled: &mut usize,
+) -> Result<i32, WakeBotError> {
+    if *rolled >= MAX_QUANTITY {
+        return Err(WakeBotError::roll(
+            RollErrorKind::TooManyDiceRolled(MAX_QUANTITY),
+            term.span.clone(),
+        ));
+    }
+    *rolled += 1;
+    Ok(rng.roll(term.faces.count()))
+}
+
+// Marks which of the given dice values a keep/drop modifier throws away
+pub fn dropped_dice<T: Ord + Copy>(values: &[T], (kind, count): (KeepKind, usize)) -> Vec<bool> {
+    let mut dropped = vec![false; values.len()];
+    let mut removed_indices = (0..values.len()).collect::<Vec<usize>>();
+    removed_indices.sort_by_key(|i| values[*i]);
+    // Removal always starts from the front, so flip the order when the high dice are the ones to go
+    if kind == KeepKind::Lowest || kind == KeepKind::DropHighest {
+        removed_indices.reverse();
+    }
+    let number_to_remove = match kind {
+        KeepKind::Highest | KeepKind::Lowest => values.len().saturating_sub(count),
+        KeepKind::DropHighest | KeepKind::DropLowest => count,
+    };
+    for i in removed_indices.into_iter().take(number_to_remove) {
+        dropped[i] = true;
+    }
+    dropped
+}
+
+fn roll_dice(
+    term: &DiceTerm,
+    crit_profile: CritProfile,
+    rng: &mut dyn DiceRng,
+) -> Result<RollResult, WakeBotError> {
+    let mut rolled = 0;
+    // Each entry counts as a single die for keep modifiers and success pools. Compounding explosions stay in
+    // one entry, every other explosion starts a new one. A rerolled die always goes with the die that
+    // replaced it.
+    let mut entries: Vec<Vec<Die>> = vec![];
+    for _ in 0..term.count {
+        let mut chain: Vec<Die> = vec![];
+        loop {
+            let mut face = roll_die(rng, term, &mut rolled)?;
+            if let Some(reroll) = term.reroll {
+                while reroll.point.matches(term.faces.value(face)) {
+                    chain.push(Die {
+                        face,
+                        value: 0,
+                        mark: DieMark::Rerolled,
+                    });
+                    face = roll_die(rng, term, &mut rolled)?;
+                    if reroll.once {
+                        break;
+                    }
+                }
+            }
+            let face_value = term.faces.value(face);
+            let value = match term.explode {
+                Some(Explode {
+                    kind: ExplodeKind::Penetrate,
+                    ..
+                }) if chain.iter().any(|die| die.mark == DieMark::Exploded) => face_value - 1,
+                _ => face_value,
+            };
+            let exploded = term.explode.is_some_and(|e| e.point.matches(face_value));
+            chain.push(Die {
+                face,
+                value,
+                mark: if exploded {
+                    DieMark::Exploded
                 } else {
-                    ""
+                    DieMark::Normal
+                },
+            });
+            if !exploded {
+                break;
+            }
+        }
+        match term.explode {
+            Some(Explode {
+                kind: ExplodeKind::Compound,
+                ..
+            }) => entries.push(chain),
+            _ => {
+                let mut entry = vec![];
+                for die in chain {
+                    let is_rerolled = die.mark == DieMark::Rerolled;
+                    entry.push(die);
+                    if !is_rerolled {
+                        entries.push(std::mem::take(&mut entry));
+                    }
+                }
+            }
+        }
+    }
+    let entry_value = |entry: &Vec<Die>| {
+        entry
+            .iter()
+            .filter(|die| die.mark != DieMark::Rerolled)
+            .map(|die| i64::from(die.value))
+            .sum::<i64>()
+    };
+    let dropped = match term.keep {
+        Some(keep) => dropped_dice(&entries.iter().map(entry_value).collect::<Vec<i64>>(), keep),
+        None => vec![false; entries.len()],
+    };
+    let crit_success = term
+        .crit_success
+        .or_else(|| crit_profile.success_point(&term.faces));
+    let crit_failure = term
+        .crit_failure
+        .or_else(|| crit_profile.failure_point(&term.faces));
+    let mut has_critical_success = false;
+    let mut has_critical_failure = false;
+    let mut results = vec![];
+    let mut marks = vec![];
+    let mut roll_total = 0;
+    let mut counted = 0;
+    let mut successes = 0;
+    let mut failures = 0;
+    for (entry, is_dropped) in entries.iter().zip(dropped) {
+        if !is_dropped {
+            for die in entry.iter().filter(|die| die.mark != DieMark::Rerolled) {
+                let face_value = term.faces.value(die.face);
+                has_critical_success |= crit_success.is_some_and(|p| p.matches(face_value));
+                has_critical_failure |= crit_failure.is_some_and(|p| p.matches(face_value));
+            }
+            let value = entry_value(entry);
+            match term.pool {
+                Some(pool) => {
+                    counted += 1;
+                    if pool.success.matches(value) {
+                        successes += 1;
+                    } else if pool.is_failure(value) || (pool.failure.is_none() && value == 1) {
+                        failures += 1;
+                    }
+                    roll_total += i64::from(pool.score(value));
+                }
+                None => roll_total += value,
+            }
+        }
+        for die in entry {
+            let is_discarded = is_dropped || die.mark == DieMark::Rerolled;
+            results.push(if is_discarded { -die.face } else { die.face });
+            marks.push(die.mark);
+        }
+    }
+    Ok(RollResult {
+        original_text: term.text.clone(),
+        non_roll_portion: term.trailing.clone(),
+        rolls: results,
+        roll_total,
+        has_critical_success,
+        has_critical_failure,
+        has_botch: term.pool.is_some() && successes == 0 && failures > 0,
+        has_glitch: term.pool.is_some() && failures * 2 > counted,
+        marks,
+        term: term.clone(),
+    })
+}
+
+// Walks the AST once, rolling each dice term as it is reached so results come out in left to right order
+pub fn evaluate(
+    expr: &Expr,
+    options: &RollOptions,
+    rolls: &mut Vec<RollResult>,
+    rng: &mut dyn DiceRng,
+) -> Result<f64, WakeBotError> {
+    match expr {
+        Expr::Number(n) => Ok(*n),
+        Expr::Dice(term) => {
+            let result = roll_dice(term, options.crit_profile, rng)?;
+            let total = result.roll_total as f64;
+            rolls.push(result);
+            Ok(total)
+        }
+        Expr::Negate(inner) => Ok(-evaluate(inner, options, rolls, rng)?),
+        Expr::Binary(op, left, right, span) => {
+            let left = evaluate(left, options, rolls, rng)?;
+            let right = evaluate(right, options, rolls, rng)?;
+            match op {
+                BinaryOp::Add => Ok(left + right),
+                BinaryOp::Subtract => Ok(left - right),
+                BinaryOp::Multiply => Ok(left * right),
+                BinaryOp::Divide if right == 0.0 => Err(WakeBotError::roll(
+                    RollErrorKind::DivideByZero,
+                    span.clone(),
+                )),
+                BinaryOp::Divide => Ok(left / right),
+            }
+        }
+    }
+}
+
+// Evaluates plain arithmetic (no dice), used to show each roll alongside its trailing modifiers
+pub fn evaluate_arithmetic(input: &str) -> Result<f64, WakeBotError> {
+    evaluate(
+        &parse_rolls(input)?,
+        &RollOptions::default(),
+        &mut vec![],
+        &mut rand::rngs::OsRng,
+    )
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum D20Mode {
+    #[default]
+    Straight,
+    Advantage,
+    Disadvantage,
+    // Elven Accuracy, rolling three dice with advantage instead of two
+    ElvenAccuracy,
+}
+
+// Which natural rolls count as critical when a dice term doesn't give its own cs/cf targets
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum CritProfile {
+    // 20 and 1 on a d20
+    #[default]
+    Standard,
+    // Crits from the given number up on a d20, still failing on a 1
+    Range(i32),
+    // Highest and lowest face of any die
+    AnyDie,
+    Off,
+}
+
+impl CritProfile {
+    pub fn parse(input: &str) -> Option<CritProfile> {
+        match input.trim().to_lowercase().as_str() {
+            "standard" | "default" => Some(CritProfile::Standard),
+            "max" | "any" => Some(CritProfile::AnyDie),
+            "none" | "off" => Some(CritProfile::Off),
+            other => match other.parse::<i32>() {
+                Ok(n) if (2..=20).contains(&n) => Some(CritProfile::Range(n)),
+                _ => None,
+            },
+        }
+    }
+
+    fn success_point(&self, faces: &Faces) -> Option<ComparePoint> {
+        let (op, value) = match (self, faces) {
+            (CritProfile::Standard, Faces::Numbered(20)) => (CompareOp::Exactly, 20),
+            (CritProfile::Range(n), Faces::Numbered(20)) => (CompareOp::AtLeast, *n),
+            (CritProfile::AnyDie, _) => (CompareOp::Exactly, faces.max_value()),
+            _ => return None,
+        };
+        Some(ComparePoint { op, value })
+    }
+
+    fn failure_point(&self, faces: &Faces) -> Option<ComparePoint> {
+        let value = match (self, faces) {
+            (CritProfile::Standard | CritProfile::Range(_), Faces::Numbered(20)) => 1,
+            (CritProfile::AnyDie, _) => faces.min_value(),
+            _ => return None,
+        };
+        Some(ComparePoint {
+            op: CompareOp::Exactly,
+            value,
+        })
+    }
+}
+
+impl fmt::Display for CritProfile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CritProfile::Standard => write!(f, "standard"),
+            CritProfile::Range(n) => write!(f, "{}", n),
+            CritProfile::AnyDie => write!(f, "max"),
+            CritProfile::Off => write!(f, "none"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct RollOptions {
+    pub crit_profile: CritProfile,
+    pub d20_mode: D20Mode,
+    // Halfling Luck, rerolling natural 1s on d20s once
+    pub halfling_luck: bool,
+}
+
+// Splits a trailing "adv" or "dis" from a roll
+pub fn split_d20_mode(input: &str) -> (&str, Option<D20Mode>) {
+    let trimmed = input.trim_end();
+    for (suffix, mode) in [("adv", D20Mode::Advantage), ("dis", D20Mode::Disadvantage)] {
+        let split = trimmed.len().saturating_sub(suffix.len());
+        if split > 0 && trimmed.is_char_boundary(split) {
+            let (rest, end) = trimmed.split_at(split);
+            if end.eq_ignore_ascii_case(suffix) {
+                return (rest.trim_end(), Some(mode));
+            }
+        }
+    }
+    (input, None)
+}
+
+// Turns single d20s into advantage or disadvantage rolls, and adds the halfling reroll
+pub fn apply_d20_options(expr: &mut Expr, mode: D20Mode, halfling_luck: bool) {
+    match expr {
+        Expr::Number(_) => {}
+        Expr::Dice(term) => {
+            if term.faces != Faces::Numbered(20) {
+                return;
+            }
+            if term.count == 1 && term.keep.is_none() {
+                let (count, kind) = match mode {
+                    D20Mode::Straight => (1, None),
+                    D20Mode::Advantage => (2, Some(KeepKind::Highest)),
+                    D20Mode::Disadvantage => (2, Some(KeepKind::Lowest)),
+                    D20Mode::ElvenAccuracy => (3, Some(KeepKind::Highest)),
+                };
+                if let Some(kind) = kind {
+                    term.count = count;
+                    term.keep = Some((kind, 1));
+                    // Keep anything typed after the die, like crit ranges or tags, so it still shows
+                    let modifiers = term
+                        .text
+                        .find(['d', 'D'])
+                        .map_or("", |i| &term.text[i + 1..])
+                        .trim_start_matches(|c: char| c.is_ascii_digit());
+                    term.text = format!(
+                        "{}d20{}1{}",
+                        count,
+                        if kind == KeepKind::Highest {
+                            "kh"
+                        } else {
+                            "kl"
+                        },
+                        modifiers
+                    );
+                }
+            }
+            if halfling_luck && term.reroll.is_none() {
+                term.reroll = Some(Reroll {
+                    once: true,
+                    point: ComparePoint {
+                        op: CompareOp::Exactly,
+                        value: 1,
+                    },
                 });
-                let sorting_priority = result
-                    .converted_text
-                    .find(cap.get(0).unwrap().as_str())
-                    .unwrap()
-                    + input_offset;
-                // Do math here?
-                result.rolls.push(RollResult::new(
-                    String::from(dice_str.as_str()),
-                    non_roll_portion,
-                    results,
-                    roll_total,
-                    has_critical_success,
-                    has_critical_failure,
-                    sorting_priority,
-                ));
-                result.converted_text = String::from(&result.converted_text[0..start])
-                    + &roll_total.to_string()
-                    + &result.converted_text[end..];
-                // We've defined the rolls, now what do we do? Probably mutate the result instance to update its string.
-                // At what point do we determine what other math goes along with the roll?
-            }
-            Ok(None) => break,
-            Err(_) => return Err(WakeBotError::new("Error occurred while parsing roll regex")),
-        }
-    }
-
-    result
-        .rolls
-        .sort_by(|a, b| a.sorting_priority.cmp(&b.sorting_priority));
+                term.text += "ro1";
+            }
+        }
+        Expr::Negate(inner) => apply_d20_options(inner, mode, halfling_luck),
+        Expr::Binary(_, left, right, _) => {
+            apply_d20_options(left, mode, halfling_luck);
+            apply_d20_options(right, mode, halfling_luck);
+        }
+    }
+}
+
+// This accepts a roll string, which is a certain amount of numbers or rolls all separated by operators
+pub fn interpret_rolls<'a>(
+    input: &'a str,
+    rng: &mut dyn DiceRng,
+) -> Result<RollStringResult<'a>, WakeBotError> {
+    interpret_rolls_with(input, &RollOptions::default(), rng)
+}
+
+pub fn interpret_rolls_with<'a>(
+    input: &'a str,
+    options: &RollOptions,
+    rng: &mut dyn DiceRng,
+) -> Result<RollStringResult<'a>, WakeBotError> {
+    let original = input;
+    // Remove ! from beginning if it is there (legacy behavior from previously saved actions in AWS)
+    let input = input.strip_prefix('!').unwrap_or(input).trim_start();
+    // Only the front has been taken off, so this is how far into the text passed in the roll starts
+    let offset = original.len() - input.len();
+    let (input, label) = split_label(input);
+    let mut result = RollStringResult::new(input);
+    result.label = label.map(String::from);
+    let (roll_str, suffix_mode) = split_d20_mode(input);
+    // Elven Accuracy only changes how advantage is rolled, so a plain "adv" shouldn't override it
+    let d20_mode = match (suffix_mode, options.d20_mode) {
+        (Some(D20Mode::Advantage), D20Mode::ElvenAccuracy) => D20Mode::ElvenAccuracy,
+        (Some(mode), _) => mode,
+        (None, mode) => mode,
+    };
+    // Spans in errors point into the text that was passed in, not just the expression
+    let mut expr = parse_rolls(roll_str).map_err(|e| e.offset_by(offset))?;
+    apply_d20_options(&mut expr, d20_mode, options.halfling_luck);
+    result.total =
+        evaluate(&expr, options, &mut result.rolls, rng).map_err(|e| e.offset_by(offset))?;
+
+    // Substitute each dice term with its total so the full calculation can be shown
+    let mut converted_text = String::new();
+    let mut last_end = 0;
+    for roll in result.rolls.iter() {
+        converted_text += &roll_str[last_end..roll.term.span.start];
+        converted_text += &roll.roll_total.to_string();
+        last_end = roll.term.span.end;
+    }
+    converted_text += &roll_str[last_end..];
+    result.converted_text = converted_text;
 
     Ok(result)
 }
 
+// The value each die counts as, which is one less than its face for dice from a penetrating explosion
+fn die_values(roll: &RollResult) -> Vec<i32> {
+    let penetrates = roll.term.explode.map(|e| e.kind) == Some(ExplodeKind::Penetrate);
+    let mut follows_explosion = false;
+    roll.rolls
+        .iter()
+        .zip(roll.marks.iter())
+        .map(|(&roll_num, &mark)| {
+            let mut value = roll.term.faces.value(roll_num.abs());
+            if follows_explosion && penetrates && mark != DieMark::Rerolled {
+                value -= 1;
+            }
+            // A rerolled die is replaced by the next one, which keeps its place in any explosion chain
+            if mark != DieMark::Rerolled {
+                follows_explosion = mark == DieMark::Exploded;
+            }
+            value
+        })
+        .collect()
+}
+
+// Lists each die of a roll, showing explosion chains and striking through anything dropped or rerolled.
+// Success pools show hits in bold and failures in italics.
+fn format_dice(roll: &RollResult, list_every_die: bool) -> String {
+    if !list_every_die && roll.rolls.len() > MAX_LISTED_DICE {
+        return format_face_counts(roll);
+    }
+    let explode_kind = roll.term.explode.map(|e| e.kind);
+    let values = die_values(roll);
+    // Each unit is what counts as a single die, so a whole compounded chain is highlighted together
+    let mut units: Vec<(String, i64, bool)> = vec![];
+    let mut follows_explosion = false;
+    for (i, &roll_num) in roll.rolls.iter().enumerate() {
+        let mark = roll.marks[i];
+        let value = values[i];
+        let die_text =
+            roll.term.faces.symbol(value) + if mark == DieMark::Exploded { "!" } else { "" };
+        let die_text = if roll_num < 0 {
+            String::from("~~") + &die_text + "~~"
+        } else {
+            die_text
+        };
+        let continues_chain = follows_explosion && explode_kind == Some(ExplodeKind::Compound);
+        match units.last_mut() {
+            Some(unit) if continues_chain => {
+                unit.0 += "+";
+                unit.0 += &die_text;
+            }
+            _ => units.push((die_text, 0, false)),
+        }
+        if let Some(unit) = units.last_mut() {
+            if roll_num >= 0 && mark != DieMark::Rerolled {
+                unit.1 += i64::from(value);
+                unit.2 = true;
+            }
+        }
+        if mark != DieMark::Rerolled {
+            follows_explosion = mark == DieMark::Exploded;
+        }
+    }
+    units
+        .into_iter()
+        .map(|(text, value, counts)| match roll.term.pool {
+            Some(pool) if counts && pool.success.matches(value) => format!("**{}**", text),
+            Some(pool) if counts && pool.score(value) < 0 => format!("_{}_", text),
+            _ => text,
+        })
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+// Summarises a big roll by face, e.g. "1×167, 2×171", with dropped and rerolled dice only counted
+fn format_face_counts(roll: &RollResult) -> String {
+    let mut counts: BTreeMap<i32, usize> = BTreeMap::new();
+    let mut discarded = 0;
+    for (&roll_num, value) in roll.rolls.iter().zip(die_values(roll)) {
+        if roll_num < 0 {
+            discarded += 1;
+        } else {
+            *counts.entry(value).or_default() += 1;
+        }
+    }
+    let mut text = counts
+        .iter()
+        .map(|(value, count)| format!("{}×{}", value, count))
+        .collect::<Vec<String>>()
+        .join(", ");
+    if discarded > 0 {
+        text += &format!(", ~~{} discarded~~", discarded);
+    }
+    text
+}
+
+fn format_total(roll: &RollResult) -> String {
+    match roll.term.pool {
+        Some(_) if roll.roll_total == 1 => String::from("1 success"),
+        Some(_) => format!("{} successes", roll.roll_total),
+        None => roll.roll_total.to_string(),
+    }
+}
+
+fn format_markers(roll: &RollResult) -> String {
+    let mut markers = String::new();
+    if roll.has_critical_success {
+        markers += " - **CRITICAL SUCCESS!**";
+    }
+    if roll.has_critical_failure {
+        markers += " - **CRITICAL FAILURE!**";
+    }
+    if roll.has_botch {
+        markers += " - **BOTCH!**";
+    }
+    if roll.has_glitch {
+        markers += " - **GLITCH!**";
+    }
+    markers
+}
+
+// Adds up the dice totals for each tag, in the order the tags first appear
+pub fn format_tag_totals(rolls: &[RollResult]) -> String {
+    let mut totals: Vec<(&str, i64)> = vec![];
+    for roll in rolls {
+        if let Some(tag) = &roll.term.tag {
+            match totals.iter_mut().find(|(name, _)| name == tag) {
+                Some((_, total)) => *total += roll.roll_total,
+                None => totals.push((tag, roll.roll_total)),
+            }
+        }
+    }
+    if totals.is_empty() {
+        return String::new();
+    }
+    totals
+        .iter()
+        .map(|(name, total)| format!("{}: {}", name.replace("*", r"\*"), total))
+        .collect::<Vec<String>>()
+        .join(", ")
+        + "\n"
+}
+
+fn format_label(label: &Option<String>) -> String {
+    match label {
+        Some(label) => format!("**{}**\n", label.replace("*", r"\*")),
+        None => String::new(),
+    }
+}
+
+// The dice of a single roll along with its total, and any arithmetic that directly follows it
+pub fn format_roll_detail(roll: &RollResult) -> String {
+    roll_detail(roll, false)
+}
+
+fn roll_detail(roll: &RollResult, list_every_die: bool) -> String {
+    let converted_text = roll.roll_total.to_string() + &roll.non_roll_portion;
+    let result = evaluate_arithmetic(&converted_text).unwrap_or(roll.roll_total as f64);
+    format!(
+        "({} -> {}){} = {}{}",
+        format_dice(roll, list_every_die),
+        format_total(roll),
+        roll.non_roll_portion.replace("*", r"\*"),
+        result,
+        format_markers(roll)
+    )
+}
+
 pub fn format_rolls_result_new(result: RollStringResult) -> String {
-    let full_expr = ShuntingParser::parse_str(&result.converted_text).unwrap();
-    let full_result = MathContext::new().eval(&full_expr).unwrap();
+    format_rolls_result(&result, false)
+}
+
+// Every roll made for a message with all of its dice, however many there are, for the roll log
+pub fn format_rolls_in_full(results: &[RollStringResult]) -> String {
+    results
+        .iter()
+        .map(|result| format_rolls_result(result, true))
+        .collect::<Vec<String>>()
+        .join("\n\n")
+}
+
+fn format_rolls_result(result: &RollStringResult, list_every_die: bool) -> String {
     format!(
-        "{}\n{}{}**{}**",
+        "{}{}\n{}{}{}**{}**",
+        format_label(&result.label),
         result.original_text.replace("*", r"\*"),
         result.rolls.iter().fold(String::from(""), |a, b| {
             // Display each roll
-            let converted_text = b.roll_total.to_string() + &b.non_roll_portion;
-            let expr = ShuntingParser::parse_str(&converted_text).unwrap();
-            let result = MathContext::new().eval(&expr).unwrap();
-            a + &format!(
-                "{} ({} -> {}){} = {}{}{}\n",
-                b.original_text,
-                b.rolls
-                    .iter()
-                    .map(|&roll_num| {
-                        if roll_num < 0 {
-                            return String::from("~~") + &roll_num.abs().to_string() + "~~";
-                        }
-                        roll_num.to_string().replace("*", r"\*")
-                    })
-                    .collect::<Vec<String>>()
-                    .join(", "),
-                b.roll_total,
-                b.non_roll_portion,
-                result,
-                if b.has_critical_success {
-                    " - **CRITICAL SUCCESS!**"
-                } else {
-                    ""
-                },
-                if b.has_critical_failure {
-                    " - **CRITICAL FAILURE!**"
-                } else {
-                    ""
-                }
-            )
+            a + &format!("{} {}\n", b.original_text, roll_detail(b, list_every_die))
         }),
         if result.rolls.len() > 1 {
             result.converted_text.replace("*", r"\*") + "\n"
         } else {
             String::from("")
         },
-        full_result
+        format_tag_totals(&result.rolls),
+        result.total
     )
 }
+
+// Splits a trailing comment like "# Perception" from a roll
+pub fn split_label(input: &str) -> (&str, Option<&str>) {
+    match input.split_once('#') {
+        Some((roll, label)) if !label.trim().is_empty() => (roll.trim(), Some(label.trim())),
+        Some((roll, _)) => (roll.trim(), None),
+        None => (input, None),
+    }
+}
+
+// Splits a repeat prefix like "6x " or "3#" from the front of a roll, returning the count and the rest
+pub fn split_repeat(input: &str) -> Option<(usize, &str)> {
+    let digits_end = input.find(|c: char| !c.is_ascii_digit())?;
+    let count = input[..digits_end].parse::<usize>().ok()?;
+    let rest = input[digits_end..].trim_start();
+    let rest = rest.strip_prefix('x').or_else(|| rest.strip_prefix('#'))?;
+    Some((count, rest.trim_start()))
+}
+
+// Splits the label from a repeated roll like "3#1d20+5 # Attack", leaving the repeat prefix on the roll.
+// The prefix has to come off first, since it can use '#' too.
+pub fn split_repeated_label(input: &str) -> (&str, Option<&str>) {
+    let rest = split_repeat(input).map_or(input, |(_, rest)| rest);
+    let (roll, label) = split_label(rest);
+    let prefix_length = input.len() - rest.len();
+    (input[..prefix_length + roll.len()].trim(), label)
+}
+
+pub fn interpret_repeated_rolls<'a>(
+    count: usize,
+    input: &'a str,
+    options: &RollOptions,
+    rng: &mut dyn DiceRng,
+) -> Result<Vec<RollStringResult<'a>>, WakeBotError> {
+    if count == 0 || count > MAX_REPEATS {
+        return Err(WakeBotError::InvalidRepeatCount(MAX_REPEATS));
+    }
+    (0..count)
+        .map(|_| interpret_rolls_with(input, options, rng))
+        .collect()
+}
+
+// A single line summary of a roll, leaving out the expression itself
+pub fn format_roll_line(result: &RollStringResult) -> String {
+    format!(
+        "{} = **{}**",
+        result
+            .rolls
+            .iter()
+            .map(|roll| format!(
+                "({} -> {}){}",
+                format_dice(roll, false),
+                format_total(roll),
+                format_markers(roll)
+            ))
+            .collect::<Vec<String>>()
+            .join(", "),
+        result.total
+    )
+}
+
+pub fn format_repeated_rolls(
+    original_text: &str,
+    results: &[RollStringResult],
+    show_sum: bool,
+    show_sorted: bool,
+) -> String {
+    let (original_text, label) = split_repeated_label(original_text);
+    let mut text =
+        format_label(&label.map(String::from)) + &original_text.replace("*", r"\*") + "\n";
+    for (i, result) in results.iter().enumerate() {
+        text += &format!("{}. {}\n", i + 1, format_roll_line(result));
+    }
+    if show_sum {
+        text += &format!(
+            "Sum: **{}**\n",
+            results.iter().map(|result| result.total).sum::<f64>()
+        );
+    }
+    if show_sorted {
+        let mut totals = results
+            .iter()
+            .map(|result| result.total)
+            .collect::<Vec<f64>>();
+        totals.sort_by(|a, b| b.total_cmp(a));
+        text += &format!(
+            "Sorted: {}\n",
+            totals
+                .iter()
+                .map(|total| total.to_string())
+                .collect::<Vec<String>>()
+                .join(", ")
+        );
+    }
+    text
+}
+
+fn is_repeat_count(text: &str) -> bool {
+    let text = text.trim();
+    !text.is_empty() && text.chars().all(|c| c.is_ascii_digit())
+}
+
+// Finds where each roll in a message starts and where the label ends the rolls, if there is one. Separators
+// inside brackets don't start a new roll.
+fn scan_expressions(input: &str) -> (Vec<usize>, Option<usize>) {
+    let mut starts = vec![0];
+    let mut depth = 0;
+    for (i, c) in input.char_indices() {
+        let start = starts[starts.len() - 1];
+        match c {
+            '(' | '[' | '{' => depth += 1,
+            ')' | ']' | '}' => depth -= 1,
+            // Unless it's a repeat like "3#1d20"
+            '#' if !is_repeat_count(&input[start..i]) => return (starts, Some(i)),
+            ';' | ',' if depth == 0 => starts.push(i + 1),
+            _ => {}
+        }
+    }
+    (starts, None)
+}
+
+// Splits the label off a whole message of rolls, which can hold repeats like "3#1d20" before it. The rolls
+// are left where they start.
+pub fn split_message_label(input: &str) -> (&str, Option<&str>) {
+    match scan_expressions(input).1 {
+        Some(i) => {
+            let label = input[i + 1..].trim();
+            (
+                input[..i].trim_end(),
+                Some(label).filter(|label| !label.is_empty()),
+            )
+        }
+        None => (input, None),
+    }
+}
+
+// Splits a message into independent rolls separated by ';' or ',', ignoring any separators inside brackets.
+// A label runs to the end of the message, so it can have commas in it and stays with the last roll. Each
+// roll comes with the byte offset it starts at.
+fn split_expressions_at(input: &str) -> Vec<(usize, &str)> {
+    let (starts, _) = scan_expressions(input);
+    let ends = starts[1..]
+        .iter()
+        .map(|start| start - 1)
+        .chain([input.len()]);
+    let mut expressions = starts
+        .iter()
+        .zip(ends)
+        .map(|(&start, end)| {
+            let expression = &input[start..end];
+            let leading = expression.len() - expression.trim_start().len();
+            (start + leading, expression.trim())
+        })
+        .collect::<Vec<(usize, &str)>>();
+    expressions.retain(|(_, expression)| !expression.is_empty());
+    expressions
+}
+
+pub fn split_expressions(input: &str) -> Vec<&str> {
+    split_expressions_at(input)
+        .into_iter()
+        .map(|(_, expression)| expression)
+        .collect()
+}
+
+// Checks that every expression in a message would parse, without rolling anything
+pub fn validate_rolls(input: &str) -> Result<(), WakeBotError> {
+    let expressions = split_expressions_at(input);
+    if expressions.is_empty() {
+        return Err(WakeBotError::EmptyRoll);
+    }
+    for (start, expression) in expressions {
+        // Taking off a repeat prefix leaves the rest of the roll in place, so only the front moves
+        let rest = split_repeat(expression).map_or(expression, |(_, rest)| rest);
+        let offset = start + expression.len() - rest.len();
+        let roll_str = split_d20_mode(split_label(rest).0).0;
+        parse_rolls(roll_str).map_err(|e| e.offset_by(offset))?;
+    }
+    Ok(())
+}
+
+// Finds rolls embedded in ordinary text as "[[1d20+3]]", returning the byte range of each including brackets.
+// Single brackets inside are left alone so tags like "[[2d6[fire]]]" still work.
+pub fn find_inline_rolls(input: &str) -> Vec<Range<usize>> {
+    let mut spans = vec![];
+    let mut search_from = 0;
+    while let Some(offset) = input[search_from..].find("[[") {
+        let start = search_from + offset;
+        let mut depth = 0;
+        let mut end = None;
+        let bytes = input.as_bytes();
+        let mut i = start + 2;
+        while i < bytes.len() {
+            match bytes[i] {
+                b'[' => depth += 1,
+                b']' if depth > 0 => depth -= 1,
+                b']' if bytes.get(i + 1) == Some(&b']') => {
+                    end = Some(i + 2);
+                    break;
+                }
+                _ => {}
+            }
+            i += 1;
+        }
+        match end {
+            Some(end) => {
+                spans.push(start..end);
+                search_from = end;
+            }
+            None => break,
+        }
+    }
+    spans
+}
+
+// Replaces each inline roll in the text with its total, followed by the breakdown of every roll. Also gives
+// back the rolls that worked, for luck stats and history.
+pub fn format_inline_rolls<'a>(
+    input: &'a str,
+    spans: &[Range<usize>],
+    options: &RollOptions,
+    rng: &mut dyn DiceRng,
+) -> (String, Vec<RollStringResult<'a>>) {
+    let mut prose = String::new();
+    let mut breakdowns = vec![];
+    let mut rolls = vec![];
+    let mut last_end = 0;
+    for span in spans {
+        prose += &input[last_end..span.start];
+        let roll_str = &input[span.start + 2..span.end - 2];
+        match interpret_rolls_with(roll_str, options, rng) {
+            Ok(result) => {
+                prose += &format!("**{}**", result.total);
+                breakdowns.push(format_rolls_result_new(result.clone()));
+                rolls.push(result);
+            }
+            Err(e) => {
+                prose += "**?**";
+                breakdowns.push(format_error(roll_str, &e));
+            }
+        }
+        last_end = span.end;
+    }
+    prose += &input[last_end..];
+    (prose + "\n\n" + &breakdowns.join("\n\n"), rolls)
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    // Hands out a fixed list of faces, in order
+    pub struct FixedRolls(Vec<i32>);
+
+    impl DiceRng for FixedRolls {
+        fn roll(&mut self, _sides: u32) -> i32 {
+            assert!(!self.0.is_empty(), "Ran out of faces");
+            self.0.remove(0)
+        }
+    }
+
+    pub fn fixed(values: &[i32]) -> FixedRolls {
+        FixedRolls(values.to_vec())
+    }
+
+    fn roll_with<'a>(input: &'a str, values: &[i32]) -> RollStringResult<'a> {
+        interpret_rolls(input, &mut fixed(values)).unwrap()
+    }
+
+    #[test]
+    fn same_seed_gives_same_rolls() {
+        let first = interpret_rolls("10d20!+4d6kh3", &mut seeded_rng(42)).unwrap();
+        let second = interpret_rolls("10d20!+4d6kh3", &mut seeded_rng(42)).unwrap();
+        assert_eq!(first.total, second.total);
+        let rolls = |result: &RollStringResult| {
+            result
+                .rolls
+                .iter()
+                .map(|r| r.rolls.clone())
+                .collect::<Vec<Vec<i32>>>()
+        };
+        assert_eq!(rolls(&first), rolls(&second));
+    }
+
+    #[test]
+    fn seeded_rolls_stay_in_range() {
+        let mut rng = seeded_rng(7);
+        for _ in 0..200 {
+            let result = interpret_rolls("3d8", &mut rng).unwrap();
+            assert!((3.0..=24.0).contains(&result.total));
+        }
+    }
+
+    #[test]
+    fn respects_operator_precedence() {
+        assert_eq!(roll_with("2*(1d4+1)", &[3]).total, 8.0);
+        assert_eq!(roll_with("1d6+2*3", &[1]).total, 7.0);
+        assert_eq!(roll_with("-1d6+10", &[4]).total, 6.0);
+    }
+
+    #[test]
+    fn adds_up_huge_dice_without_overflowing() {
+        let max = i32::MAX;
+        let result = roll_with("2d2147483647", &[max, max]);
+        assert_eq!(result.rolls[0].roll_total, 2 * i64::from(max));
+        let result = roll_with("3d{2000000000}", &[1, 1, 1]);
+        assert_eq!(result.rolls[0].roll_total, 6_000_000_000);
+        let result = roll_with("1d2147483647!!", &[max, max, 1]);
+        assert_eq!(result.rolls[0].roll_total, 2 * i64::from(max) + 1);
+    }
+
+    #[test]
+    fn keeps_highest_and_marks_dropped_dice() {
+        let result = roll_with("4d6kh3", &[1, 5, 3, 6]);
+        assert_eq!(result.total, 14.0);
+        assert_eq!(result.rolls[0].rolls, vec![-1, 5, 3, 6]);
+    }
+
+    #[test]
+    fn drops_lowest() {
+        assert_eq!(roll_with("4d6dl1", &[2, 2, 6, 4]).total, 12.0);
+    }
+
+    #[test]
+    fn rejects_keeping_more_dice_than_rolled() {
+        assert!(interpret_rolls("2d6kh3", &mut seeded_rng(1)).is_err());
+    }
+
+    #[test]
+    fn explodes_into_new_dice() {
+        let result = roll_with("1d6!", &[6, 6, 2]);
+        assert_eq!(result.total, 14.0);
+        assert_eq!(
+            result.rolls[0].marks,
+            vec![DieMark::Exploded, DieMark::Exploded, DieMark::Normal]
+        );
+    }
+
+    #[test]
+    fn compounding_counts_as_one_die() {
+        let result = roll_with("2d6!!kh1", &[6, 3, 5]);
+        assert_eq!(result.total, 9.0);
+        assert_eq!(result.rolls[0].rolls, vec![6, 3, -5]);
+    }
+
+    #[test]
+    fn penetrating_dice_lose_one() {
+        assert_eq!(roll_with("1d6!p", &[6, 6, 2]).total, 12.0);
+    }
+
+    #[test]
+    fn reroll_once_keeps_second_result() {
+        let result = roll_with("1d20ro1", &[1, 1]);
+        assert_eq!(result.total, 1.0);
+        assert_eq!(result.rolls[0].rolls, vec![-1, 1]);
+    }
+
+    #[test]
+    fn counts_pool_successes() {
+        let result = roll_with("5d10>=8", &[8, 10, 3, 1, 7]);
+        assert_eq!(result.total, 2.0);
+        assert!(!result.rolls[0].has_botch);
+    }
+
+    #[test]
+    fn fate_dice_range_from_minus_one_to_one() {
+        assert_eq!(roll_with("4dF", &[1, 2, 3, 3]).total, 1.0);
+    }
+
+    #[test]
+    fn flags_natural_crits() {
+        assert!(roll_with("1d20+5", &[20]).rolls[0].has_critical_success);
+        assert!(roll_with("1d20+5", &[1]).rolls[0].has_critical_failure);
+        assert!(!roll_with("1d20+5", &[19]).rolls[0].has_critical_success);
+    }
+
+    #[test]
+    fn crit_profile_widens_range() {
+        let options = RollOptions {
+            crit_profile: CritProfile::Range(19),
+            ..Default::default()
+        };
+        let result = interpret_rolls_with("1d20", &options, &mut fixed(&[19])).unwrap();
+        assert!(result.rolls[0].has_critical_success);
+    }
+
+    #[test]
+    fn rerolled_dice_are_not_kept_or_dropped() {
+        let result = roll_with("2d20kl1ro1", &[1, 15, 12]);
+        assert_eq!(result.total, 12.0);
+        assert_eq!(result.rolls[0].rolls, vec![-1, -15, 12]);
+        let result = roll_with("4d6r1dl1", &[1, 3, 4, 5, 6]);
+        assert_eq!(result.total, 15.0);
+        assert_eq!(result.rolls[0].rolls, vec![-1, -3, 4, 5, 6]);
+    }
+
+    #[test]
+    fn rerolled_dice_are_not_counted_in_pools() {
+        let result = roll_with("3d10>=7f<=2r1", &[1, 8, 5, 9]);
+        assert_eq!(result.total, 2.0);
+        assert!(!result.rolls[0].has_glitch);
+        // Only the die that replaced the 1 counts, so two failures out of three is a glitch
+        let result = roll_with("3d10>=7f<=2r1", &[1, 2, 2, 9]);
+        assert_eq!(result.total, -1.0);
+        assert!(result.rolls[0].has_glitch);
+    }
+
+    #[test]
+    fn ignores_crits_on_dropped_dice() {
+        assert!(!roll_with("2d20kh1", &[1, 15]).rolls[0].has_critical_failure);
+    }
+
+    #[test]
+    fn advantage_keeps_higher_d20() {
+        let options = RollOptions {
+            d20_mode: D20Mode::Advantage,
+            ..Default::default()
+        };
+        let result = interpret_rolls_with("1d20+2", &options, &mut fixed(&[4, 17])).unwrap();
+        assert_eq!(result.total, 19.0);
+        assert_eq!(roll_with("1d20+2 dis", &[4, 17]).total, 6.0);
+    }
+
+    #[test]
+    fn advantage_keeps_what_was_typed_after_the_die() {
+        let options = RollOptions {
+            d20_mode: D20Mode::Advantage,
+            ..Default::default()
+        };
+        let result = interpret_rolls_with("1d20cs>19", &options, &mut fixed(&[4, 19])).unwrap();
+        assert_eq!(result.rolls[0].original_text, "2d20kh1cs>19");
+        assert!(result.rolls[0].has_critical_success);
+    }
+
+    #[test]
+    fn halfling_luck_rerolls_a_one_with_disadvantage() {
+        let options = RollOptions {
+            d20_mode: D20Mode::Disadvantage,
+            halfling_luck: true,
+            ..Default::default()
+        };
+        let result = interpret_rolls_with("1d20", &options, &mut fixed(&[1, 15, 12])).unwrap();
+        assert_eq!(result.total, 12.0);
+        assert_eq!(result.rolls[0].rolls, vec![-1, -15, 12]);
+        let result = interpret_rolls_with("1d20", &options, &mut fixed(&[15, 1, 12])).unwrap();
+        assert_eq!(result.total, 12.0);
+    }
+
+    #[test]
+    fn d20_modes_ignore_non_ascii_endings() {
+        assert_eq!(split_d20_mode("1d2éé"), ("1d2éé", None));
+        assert_eq!(split_d20_mode("1d20 éé"), ("1d20 éé", None));
+        assert_eq!(
+            split_d20_mode("1d20 adv"),
+            ("1d20", Some(D20Mode::Advantage))
+        );
+        assert!(validate_rolls("1d2éé").is_err());
+    }
+
+    #[test]
+    fn reports_invalid_rolls() {
+        let mut rng = seeded_rng(1);
+        assert!(interpret_rolls("1d6/0", &mut rng).is_err());
+        assert!(interpret_rolls("1001d6", &mut rng).is_err());
+        assert!(interpret_rolls("1d6+", &mut rng).is_err());
+        assert!(interpret_rolls("1d6kh1kh1", &mut rng).is_err());
+    }
+
+    #[test]
+    fn errors_point_at_the_problem() {
+        let mut rng = seeded_rng(1);
+        let error = interpret_rolls("!1d6+*2 # attack", &mut rng).unwrap_err();
+        assert_eq!(
+            error,
+            WakeBotError::roll(RollErrorKind::UnexpectedToken(String::from("*")), 5..6)
+        );
+        let error = interpret_rolls("1d6/(2-2)", &mut rng).unwrap_err();
+        assert_eq!(error.span(), Some(3..4));
+        let error = interpret_rolls("(1d6+2", &mut rng).unwrap_err();
+        assert_eq!(error.span(), Some(0..1));
+        assert_eq!(
+            format_error("1d6+*2", &interpret_rolls("1d6+*2", &mut rng).unwrap_err()),
+            "```\n1d6+*2\n    ^\n```\nErr: Unexpected '*' in roll"
+        );
+        // Later expressions and repeated rolls point into the whole message
+        let error = validate_rolls("1d20; 3x  1d6+*2").unwrap_err();
+        assert_eq!(error.span(), Some(14..15));
+        let error = interpret_rolls("!  1d6+*2", &mut rng).unwrap_err();
+        assert_eq!(error.span(), Some(7..8));
+    }
+
+    #[test]
+    fn caps_dice_rolled_by_explosions() {
+        let mut rng = fixed(&[1; MAX_QUANTITY + 1]);
+        assert!(interpret_rolls("1d1!", &mut rng).is_err());
+    }
+
+    #[test]
+    fn summarises_large_rolls_by_face() {
+        let faces = [vec![1; 30], vec![6; 30]].concat();
+        let text = format_rolls_result_new(roll_with("60d6", &faces));
+        assert!(text.contains("(1×30, 6×30 -> 210)"));
+        let logged = format_rolls_in_full(&[roll_with("60d6", &faces)]);
+        assert!(logged.contains(&[vec!["1"; 30], vec!["6"; 30]].concat().join(", ")));
+    }
+
+    #[test]
+    fn face_counts_use_penetrated_values() {
+        let faces = [vec![6, 3], vec![2; 50]].concat();
+        let text = format_rolls_result_new(roll_with("51d6!p", &faces));
+        assert!(text.contains("(2×51, 6×1 -> 108)"));
+    }
+
+    #[test]
+    fn splits_expressions_and_labels() {
+        assert_eq!(
+            split_expressions("1d20+5; 2d6, 1d4"),
+            vec!["1d20+5", "2d6", "1d4"]
+        );
+        assert_eq!(split_label("1d20+5 # attack"), ("1d20+5", Some("attack")));
+        assert_eq!(split_repeat("6x 4d6kh3"), Some((6, "4d6kh3")));
+        assert_eq!(split_repeat("4d6kh3"), None);
+    }
+
+    #[test]
+    fn labels_can_follow_repeats_and_hold_separators() {
+        assert_eq!(
+            split_repeated_label("3#1d20+5 # attack"),
+            ("3#1d20+5", Some("attack"))
+        );
+        assert_eq!(split_repeated_label("3x 1d20"), ("3x 1d20", None));
+        assert_eq!(
+            split_expressions("1d20 # Attack, with advantage"),
+            vec!["1d20 # Attack, with advantage"]
+        );
+        assert_eq!(
+            split_expressions("3#1d20, 2#1d6 # damage; twice"),
+            vec!["3#1d20", "2#1d6 # damage; twice"]
+        );
+        let results = interpret_repeated_rolls(
+            2,
+            "1d20+5 # attack",
+            &RollOptions::default(),
+            &mut fixed(&[4, 9]),
+        );
+        let text = format_repeated_rolls("2#1d20+5 # attack", &results.unwrap(), false, false);
+        assert!(text.starts_with("**attack**\n2#1d20+5\n"));
+    }
+
+    #[test]
+    fn finds_inline_rolls() {
+        let text = "I swing [[1d20+5]] and hit for [[1d8[slashing]]]";
+        let spans = find_inline_rolls(text);
+        assert_eq!(spans.len(), 2);
+        assert_eq!(&text[spans[0].clone()], "[[1d20+5]]");
+        assert_eq!(&text[spans[1].clone()], "[[1d8[slashing]]]");
+    }
+
+    #[test]
+    fn crit_profiles_round_trip() {
+        for profile in ["standard", "max", "none", "19"] {
+            let parsed = CritProfile::parse(profile).unwrap();
+            assert_eq!(CritProfile::parse(&parsed.to_string()), Some(parsed));
+        }
+        assert_eq!(CritProfile::parse("1"), None);
+    }
+}
@@ -0,0 +1,449 @@
+use crate::luck::{DieStats, LuckStats};
+use crate::storage::{
+    action_not_found, roll_not_found, Action, ActionScope, RollRecord, Storage, WakeBotDbError,
+};
+use async_trait::async_trait;
+use rusqlite::{params, Connection, OptionalExtension};
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS actions (
+        guild TEXT NOT NULL,
+        -- Empty for server actions
+        user_id TEXT NOT NULL,
+        name TEXT NOT NULL,
+        owner_id TEXT NOT NULL,
+        roll TEXT NOT NULL,
+        PRIMARY KEY (guild, user_id, name)
+    );
+    CREATE TABLE IF NOT EXISTS counters (
+        name TEXT PRIMARY KEY,
+        count INTEGER NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS crit_profiles (
+        scope TEXT PRIMARY KEY,
+        profile TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS roll_records (
+        id TEXT PRIMARY KEY,
+        expression TEXT NOT NULL,
+        user_id TEXT NOT NULL,
+        user_name TEXT NOT NULL,
+        channel_id TEXT NOT NULL,
+        timestamp INTEGER NOT NULL,
+        result TEXT NOT NULL,
+        seed INTEGER NOT NULL,
+        is_private INTEGER NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS variables (
+        guild TEXT NOT NULL,
+        user_id TEXT NOT NULL,
+        name TEXT NOT NULL,
+        value TEXT NOT NULL,
+        PRIMARY KEY (guild, user_id, name)
+    );
+    CREATE TABLE IF NOT EXISTS luck_stats (
+        scope TEXT NOT NULL,
+        user_name TEXT NOT NULL,
+        sides INTEGER NOT NULL,
+        rolled INTEGER NOT NULL,
+        total INTEGER NOT NULL,
+        highest INTEGER NOT NULL,
+        lowest INTEGER NOT NULL,
+        PRIMARY KEY (scope, sides)
+    );
+";
+
+// The guild and user columns an action is stored under. Global actions only exist in DynamoDB.
+fn scope_columns(scope: &ActionScope) -> Option<(&str, &str)> {
+    match scope {
+        ActionScope::Server { guild } => Some((guild, "")),
+        ActionScope::Personal { guild, user_id } => Some((guild, user_id)),
+        ActionScope::Global => None,
+    }
+}
+
+// Stores everything in a single SQLite file, for running the bot without AWS
+pub struct SqliteStorage {
+    // Queries are quick enough that holding the lock while one runs is fine
+    connection: Mutex<Connection>,
+}
+
+impl SqliteStorage {
+    // Creates the file and its tables if they don't exist yet
+    pub fn open(path: &str) -> Result<Self, WakeBotDbError> {
+        let connection = Connection::open(path)?;
+        connection.execute_batch(SCHEMA)?;
+        Ok(SqliteStorage {
+            connection: Mutex::new(connection),
+        })
+    }
+
+    // Gathers the rows matching a condition on one parameter into each user's name and stats, keyed by scope
+    fn luck_stats_where(
+        &self,
+        condition: &str,
+        value: &str,
+    ) -> Result<BTreeMap<String, (String, LuckStats)>, WakeBotDbError> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare(&format!(
+            "SELECT scope, user_name, sides, rolled, total, highest, lowest
+                FROM luck_stats WHERE {}",
+            condition
+        ))?;
+        let rows = statement.query_map(params![value], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, u32>(2)?,
+                DieStats {
+                    rolled: row.get::<_, i64>(3)? as u64,
+                    total: row.get::<_, i64>(4)? as u64,
+                    highest: row.get::<_, i64>(5)? as u64,
+                    lowest: row.get::<_, i64>(6)? as u64,
+                },
+            ))
+        })?;
+        let mut users: BTreeMap<String, (String, LuckStats)> = BTreeMap::new();
+        for row in rows {
+            let (scope, user_name, sides, die) = row?;
+            let user = users.entry(scope).or_default();
+            user.0 = user_name;
+            user.1.dice.insert(sides, die);
+        }
+        Ok(users)
+    }
+}
+
+#[async_trait]
+impl Storage for SqliteStorage {
+    async fn get_scoped_action(
+        &self,
+        scope: &ActionScope,
+        action_name: &str,
+    ) -> Result<Action, WakeBotDbError> {
+        let (guild, user_id) = scope_columns(scope).ok_or_else(action_not_found)?;
+        self.connection
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT owner_id, roll FROM actions WHERE guild = ?1 AND user_id = ?2 AND name = ?3",
+                params![guild, user_id, action_name],
+                |row| {
+                    Ok(Action {
+                        name: String::from(action_name),
+                        roll: row.get(1)?,
+                        scope: scope.clone(),
+                        owner_id: row.get(0)?,
+                    })
+                },
+            )
+            .optional()?
+            .ok_or_else(action_not_found)
+    }
+
+    async fn add_or_update_action(&self, action: &Action) -> Result<(), WakeBotDbError> {
+        let (guild, user_id) = scope_columns(&action.scope).ok_or_else(action_not_found)?;
+        self.connection.lock().unwrap().execute(
+            "INSERT INTO actions (guild, user_id, name, owner_id, roll) VALUES (?1, ?2, ?3, ?4, ?5)
+                ON CONFLICT (guild, user_id, name)
+                DO UPDATE SET owner_id = excluded.owner_id, roll = excluded.roll",
+            params![guild, user_id, action.name, action.owner_id, action.roll],
+        )?;
+        Ok(())
+    }
+
+    async fn delete_action(
+        &self,
+        scope: &ActionScope,
+        action_name: &str,
+    ) -> Result<(), WakeBotDbError> {
+        let (guild, user_id) = scope_columns(scope).ok_or_else(action_not_found)?;
+        self.connection.lock().unwrap().execute(
+            "DELETE FROM actions WHERE guild = ?1 AND user_id = ?2 AND name = ?3",
+            params![guild, user_id, action_name],
+        )?;
+        Ok(())
+    }
+
+    async fn list_actions(
+        &self,
+        guild: &str,
+        user_id: &str,
+    ) -> Result<Vec<Action>, WakeBotDbError> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare(
+            "SELECT user_id, name, owner_id, roll FROM actions
+                WHERE guild = ?1 AND (user_id = '' OR user_id = ?2)",
+        )?;
+        let rows = statement.query_map(params![guild, user_id], |row| {
+            let action_user_id: String = row.get(0)?;
+            let scope = if action_user_id.is_empty() {
+                ActionScope::Server {
+                    guild: String::from(guild),
+                }
+            } else {
+                ActionScope::Personal {
+                    guild: String::from(guild),
+                    user_id: action_user_id,
+                }
+            };
+            Ok(Action {
+                name: row.get(1)?,
+                roll: row.get(3)?,
+                scope,
+                owner_id: row.get(2)?,
+            })
+        })?;
+        Ok(rows.collect::<Result<Vec<Action>, rusqlite::Error>>()?)
+    }
+
+    async fn increment_counter(&self, name: &str) -> Result<i32, WakeBotDbError> {
+        Ok(self.connection.lock().unwrap().query_row(
+            "INSERT INTO counters (name, count) VALUES (?1, 1)
+                ON CONFLICT (name) DO UPDATE SET count = count + 1
+                RETURNING count",
+            params![name],
+            |row| row.get(0),
+        )?)
+    }
+
+    async fn get_crit_profile(&self, scope: &str) -> Result<Option<String>, WakeBotDbError> {
+        Ok(self
+            .connection
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT profile FROM crit_profiles WHERE scope = ?1",
+                params![scope],
+                |row| row.get(0),
+            )
+            .optional()?)
+    }
+
+    async fn set_crit_profile(&self, scope: &str, profile: &str) -> Result<(), WakeBotDbError> {
+        self.connection.lock().unwrap().execute(
+            "INSERT INTO crit_profiles (scope, profile) VALUES (?1, ?2)
+                ON CONFLICT (scope) DO UPDATE SET profile = excluded.profile",
+            params![scope, profile],
+        )?;
+        Ok(())
+    }
+
+    async fn add_roll_record(&self, record: &RollRecord) -> Result<(), WakeBotDbError> {
+        // SQLite integers are signed, so the seed is stored with its bits reinterpreted
+        let inserted = self.connection.lock().unwrap().execute(
+            "INSERT INTO roll_records
+                (id, expression, user_id, user_name, channel_id, timestamp, result, seed, is_private)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)
+                ON CONFLICT (id) DO NOTHING",
+            params![
+                record.id,
+                record.expression,
+                record.user_id,
+                record.user_name,
+                record.channel_id,
+                record.timestamp,
+                record.result,
+                record.seed as i64,
+                record.is_private
+            ],
+        )?;
+        if inserted == 0 {
+            return Err(WakeBotDbError::AlreadyExists);
+        }
+        Ok(())
+    }
+
+    async fn get_roll_record(&self, id: &str) -> Result<RollRecord, WakeBotDbError> {
+        self.connection
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT expression, user_id, user_name, channel_id, timestamp, result, seed, is_private
+                    FROM roll_records WHERE id = ?1",
+                params![id],
+                |row| {
+                    Ok(RollRecord {
+                        id: String::from(id),
+                        expression: row.get(0)?,
+                        user_id: row.get(1)?,
+                        user_name: row.get(2)?,
+                        channel_id: row.get(3)?,
+                        timestamp: row.get(4)?,
+                        result: row.get(5)?,
+                        seed: row.get::<_, i64>(6)? as u64,
+                        is_private: row.get(7)?,
+                    })
+                },
+            )
+            .optional()?
+            .ok_or_else(roll_not_found)
+    }
+
+    async fn get_variable(
+        &self,
+        guild: &str,
+        user_id: &str,
+        name: &str,
+    ) -> Result<Option<String>, WakeBotDbError> {
+        Ok(self
+            .connection
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT value FROM variables WHERE guild = ?1 AND user_id = ?2 AND name = ?3",
+                params![guild, user_id, name],
+                |row| row.get(0),
+            )
+            .optional()?)
+    }
+
+    async fn set_variable(
+        &self,
+        guild: &str,
+        user_id: &str,
+        name: &str,
+        value: &str,
+    ) -> Result<(), WakeBotDbError> {
+        self.connection.lock().unwrap().execute(
+            "INSERT INTO variables (guild, user_id, name, value) VALUES (?1, ?2, ?3, ?4)
+                ON CONFLICT (guild, user_id, name) DO UPDATE SET value = excluded.value",
+            params![guild, user_id, name, value],
+        )?;
+        Ok(())
+    }
+
+    async fn delete_variable(
+        &self,
+        guild: &str,
+        user_id: &str,
+        name: &str,
+    ) -> Result<(), WakeBotDbError> {
+        self.connection.lock().unwrap().execute(
+            "DELETE FROM variables WHERE guild = ?1 AND user_id = ?2 AND name = ?3",
+            params![guild, user_id, name],
+        )?;
+        Ok(())
+    }
+
+    async fn list_variables(
+        &self,
+        guild: &str,
+        user_id: &str,
+    ) -> Result<Vec<(String, String)>, WakeBotDbError> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare(
+            "SELECT name, value FROM variables WHERE guild = ?1 AND user_id = ?2 ORDER BY name",
+        )?;
+        let rows = statement.query_map(params![guild, user_id], |row| {
+            Ok((row.get(0)?, row.get(1)?))
+        })?;
+        Ok(rows.collect::<Result<Vec<(String, String)>, rusqlite::Error>>()?)
+    }
+
+    async fn get_luck_stats(&self, scope: &str) -> Result<LuckStats, WakeBotDbError> {
+        let users = self.luck_stats_where("scope = ?1", scope)?;
+        Ok(users
+            .into_values()
+            .next()
+            .map(|(_, stats)| stats)
+            .unwrap_or_default())
+    }
+
+    async fn add_luck_stats(
+        &self,
+        scope: &str,
+        user_name: &str,
+        stats: &LuckStats,
+    ) -> Result<(), WakeBotDbError> {
+        let mut connection = self.connection.lock().unwrap();
+        let transaction = connection.transaction()?;
+        // Every die size the user has rolled keeps the same name, not just the ones being added to
+        transaction.execute(
+            "UPDATE luck_stats SET user_name = ?2 WHERE scope = ?1",
+            params![scope, user_name],
+        )?;
+        for (sides, die) in stats.dice.iter() {
+            transaction.execute(
+                "INSERT INTO luck_stats
+                    (scope, user_name, sides, rolled, total, highest, lowest)
+                    VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+                    ON CONFLICT (scope, sides) DO UPDATE SET
+                        rolled = rolled + excluded.rolled,
+                        total = total + excluded.total,
+                        highest = highest + excluded.highest,
+                        lowest = lowest + excluded.lowest",
+                params![
+                    scope,
+                    user_name,
+                    sides,
+                    die.rolled as i64,
+                    die.total as i64,
+                    die.highest as i64,
+                    die.lowest as i64
+                ],
+            )?;
+        }
+        transaction.commit()?;
+        Ok(())
+    }
+
+    async fn get_all_luck_stats(
+        &self,
+        prefix: &str,
+    ) -> Result<Vec<(String, LuckStats)>, WakeBotDbError> {
+        // substr avoids having to escape the wildcards LIKE would treat specially
+        let users = self.luck_stats_where("substr(scope, 1, length(?1)) = ?1", prefix)?;
+        Ok(users.into_values().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::tests;
+
+    // Every test gets its own database, which goes away when the connection closes
+    fn open() -> SqliteStorage {
+        SqliteStorage::open(":memory:").unwrap()
+    }
+
+    #[tokio::test]
+    async fn actions_follow_scope_precedence() {
+        tests::actions_follow_scope_precedence(&open()).await;
+    }
+
+    #[tokio::test]
+    async fn counters_count_up() {
+        tests::counters_count_up(&open()).await;
+    }
+
+    #[tokio::test]
+    async fn crit_profiles_are_replaced() {
+        tests::crit_profiles_are_replaced(&open()).await;
+    }
+
+    #[tokio::test]
+    async fn roll_records_are_never_overwritten() {
+        tests::roll_records_are_never_overwritten(&open()).await;
+    }
+
+    #[tokio::test]
+    async fn luck_stats_add_up() {
+        tests::luck_stats_add_up(&open()).await;
+    }
+
+    #[tokio::test]
+    async fn variables_belong_to_one_user() {
+        tests::variables_belong_to_one_user(&open()).await;
+    }
+
+    #[tokio::test]
+    async fn global_actions_are_not_stored() {
+        let storage = open();
+        let action = Action::new("old", "1d6", ActionScope::Global, "");
+        assert!(storage.add_or_update_action(&action).await.is_err());
+    }
+}
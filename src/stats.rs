@@ -0,0 +1,519 @@
+use crate::errors::{RollErrorKind, WakeBotError};
+use crate::rolls::{
+    apply_d20_options, dropped_dice, evaluate, parse_rolls, split_d20_mode, split_label, BinaryOp,
+    DiceTerm, Expr, RollOptions,
+};
+
+// Rough cap on the work done computing an exact distribution before falling back to simulation
+const MAX_EXACT_WORK: usize = 2_000_000;
+const MAX_FACES: u32 = 10_000;
+const MAX_SAMPLES: usize = 20_000;
+const MIN_SAMPLES: usize = 1_000;
+// Total dice rolled across every sample of a simulation
+const MAX_SIMULATED_DICE: usize = 2_000_000;
+const HISTOGRAM_ROWS: usize = 20;
+const HISTOGRAM_WIDTH: f64 = 30.0;
+
+// Possible totals paired with their probability, sorted by total
+type Distribution = Vec<(f64, f64)>;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TargetOp {
+    AtLeast,
+    AtMost,
+    Greater,
+    Less,
+    Exactly,
+}
+
+// Threshold for !odds. Unlike dice modifiers these use the normal maths meaning, so '>' is strict.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Target {
+    pub op: TargetOp,
+    pub value: f64,
+}
+
+impl Target {
+    fn matches(&self, n: f64) -> bool {
+        match self.op {
+            TargetOp::AtLeast => n >= self.value,
+            TargetOp::AtMost => n <= self.value,
+            TargetOp::Greater => n > self.value,
+            TargetOp::Less => n < self.value,
+            TargetOp::Exactly => n == self.value,
+        }
+    }
+
+    fn symbol(&self) -> &'static str {
+        match self.op {
+            TargetOp::AtLeast => "≥",
+            TargetOp::AtMost => "≤",
+            TargetOp::Greater => ">",
+            TargetOp::Less => "<",
+            TargetOp::Exactly => "=",
+        }
+    }
+}
+
+pub struct RollStats {
+    pub distribution: Distribution,
+    // False when the distribution was estimated by rolling many times
+    pub exact: bool,
+    pub samples: usize,
+    pub mean: f64,
+    pub std_dev: f64,
+    pub min: f64,
+    pub max: f64,
+}
+
+impl RollStats {
+    pub fn probability(&self, target: &Target) -> f64 {
+        self.distribution
+            .iter()
+            .filter(|(value, _)| target.matches(*value))
+            .map(|(_, p)| p)
+            .sum()
+    }
+}
+
+// Splits the target off an !odds roll, e.g. "1d20+5 >= 15". The comparison needs a space before it
+// so it isn't confused with a success pool like "8d10>=7".
+pub fn split_target(input: &str) -> Result<(&str, Target), WakeBotError> {
+    let missing = || WakeBotError::MissingTarget;
+    let (position, _) = input
+        .char_indices()
+        .rev()
+        .find(|(i, c)| {
+            matches!(c, '>' | '<' | '=')
+                && input[..*i].ends_with(char::is_whitespace)
+                && !input[..*i].trim().is_empty()
+        })
+        .ok_or_else(missing)?;
+    let rest = &input[position..];
+    let (op, length) = if rest.starts_with(">=") {
+        (TargetOp::AtLeast, 2)
+    } else if rest.starts_with("<=") {
+        (TargetOp::AtMost, 2)
+    } else if rest.starts_with('>') {
+        (TargetOp::Greater, 1)
+    } else if rest.starts_with('<') {
+        (TargetOp::Less, 1)
+    } else {
+        (TargetOp::Exactly, 1)
+    };
+    let after_op = &rest[length..];
+    let value_text = after_op.trim();
+    let value = value_text.parse::<f64>().map_err(|_| {
+        let start = position + length + after_op.len() - after_op.trim_start().len();
+        WakeBotError::roll(
+            RollErrorKind::InvalidTarget(String::from(value_text)),
+            start..start + value_text.len(),
+        )
+    })?;
+    // The expression is left where it starts, so error spans in it still line up with the input
+    Ok((input[..position].trim_end(), Target { op, value }))
+}
+
+fn normalize(mut pairs: Vec<(f64, f64)>) -> Distribution {
+    pairs.sort_by(|a, b| a.0.total_cmp(&b.0));
+    let mut distribution: Distribution = vec![];
+    for (value, p) in pairs {
+        match distribution.last_mut() {
+            Some(last) if last.0 == value => last.1 += p,
+            _ => distribution.push((value, p)),
+        }
+    }
+    distribution
+}
+
+// Charges work against the budget, returning false once it has run out
+fn spend(budget: &mut usize, work: usize) -> bool {
+    match budget.checked_sub(work) {
+        Some(remaining) => {
+            *budget = remaining;
+            true
+        }
+        None => false,
+    }
+}
+
+fn combine(
+    left: &Distribution,
+    right: &Distribution,
+    budget: &mut usize,
+    op: impl Fn(f64, f64) -> f64,
+) -> Option<Distribution> {
+    if !spend(budget, left.len().saturating_mul(right.len())) {
+        return None;
+    }
+    let mut pairs = Vec::with_capacity(left.len() * right.len());
+    for (x, p) in left {
+        pairs.extend(right.iter().map(|(y, q)| (op(*x, *y), p * q)));
+    }
+    Some(normalize(pairs))
+}
+
+// Distribution of a single die, after any rerolls
+fn die_distribution(term: &DiceTerm) -> Option<Distribution> {
+    let count = term.faces.count();
+    if count == 0 || count > MAX_FACES {
+        return None;
+    }
+    let values = (1..=count as i32)
+        .map(|face| term.faces.value(face))
+        .collect::<Vec<i32>>();
+    let chance = 1.0 / count as f64;
+    let pairs = match term.reroll {
+        None => values.iter().map(|v| (*v as f64, chance)).collect(),
+        Some(reroll) => {
+            let matching = values.iter().filter(|v| reroll.point.matches(**v)).count() as f64;
+            if reroll.once {
+                // Either the first roll stands, or it matched and the second roll stands no matter what
+                values
+                    .iter()
+                    .map(|v| {
+                        let first = if reroll.point.matches(*v) {
+                            0.0
+                        } else {
+                            chance
+                        };
+                        (*v as f64, first + matching * chance * chance)
+                    })
+                    .collect()
+            } else {
+                let remaining = count as f64 - matching;
+                values
+                    .iter()
+                    .filter(|v| !reroll.point.matches(**v))
+                    .map(|v| (*v as f64, 1.0 / remaining))
+                    .collect()
+            }
+        }
+    };
+    Some(normalize(pairs))
+}
+
+fn term_distribution(term: &DiceTerm, budget: &mut usize) -> Option<Distribution> {
+    // Explosions have no upper limit, so those are left to simulation
+    if term.explode.is_some() {
+        return None;
+    }
+    let die = die_distribution(term)?;
+    let score = |value: i32| match term.pool {
+        Some(pool) => pool.score(value.into()),
+        None => value,
+    };
+    match term.keep {
+        None => {
+            let per_die = normalize(
+                die.iter()
+                    .map(|(v, p)| (score(*v as i32) as f64, *p))
+                    .collect(),
+            );
+            let mut total = vec![(0.0, 1.0)];
+            for _ in 0..term.count {
+                total = combine(&total, &per_die, budget, |a, b| a + b)?;
+            }
+            Some(total)
+        }
+        Some(keep) => {
+            // Which dice get kept depends on all of them at once, so walk every possible outcome
+            let outcomes = die.len().checked_pow(term.count as u32)?;
+            if !spend(budget, outcomes.saturating_mul(term.count.max(1))) {
+                return None;
+            }
+            let mut pairs = Vec::with_capacity(outcomes);
+            let mut indices = vec![0; term.count];
+            for _ in 0..outcomes {
+                let values = indices
+                    .iter()
+                    .map(|i| die[*i].0 as i32)
+                    .collect::<Vec<i32>>();
+                let p = indices.iter().map(|i| die[*i].1).product::<f64>();
+                let total = values
+                    .iter()
+                    .zip(dropped_dice(&values, keep))
+                    .filter(|(_, is_dropped)| !is_dropped)
+                    .map(|(v, _)| score(*v))
+                    .sum::<i32>();
+                pairs.push((total as f64, p));
+                for index in indices.iter_mut() {
+                    *index += 1;
+                    if *index < die.len() {
+                        break;
+                    }
+                    *index = 0;
+                }
+            }
+            Some(normalize(pairs))
+        }
+    }
+}
+
+// Returns Ok(None) when the roll is too big or too open-ended to work out exactly
+fn exact_distribution(
+    expr: &Expr,
+    budget: &mut usize,
+) -> Result<Option<Distribution>, WakeBotError> {
+    match expr {
+        Expr::Number(n) => Ok(Some(vec![(*n, 1.0)])),
+        Expr::Dice(term) => Ok(term_distribution(term, budget)),
+        Expr::Negate(inner) => Ok(exact_distribution(inner, budget)?
+            .map(|d| normalize(d.into_iter().map(|(v, p)| (-v, p)).collect()))),
+        Expr::Binary(op, left, right, span) => {
+            let left = match exact_distribution(left, budget)? {
+                Some(left) => left,
+                None => return Ok(None),
+            };
+            let right = match exact_distribution(right, budget)? {
+                Some(right) => right,
+                None => return Ok(None),
+            };
+            Ok(match op {
+                BinaryOp::Add => combine(&left, &right, budget, |a, b| a + b),
+                BinaryOp::Subtract => combine(&left, &right, budget, |a, b| a - b),
+                BinaryOp::Multiply => combine(&left, &right, budget, |a, b| a * b),
+                BinaryOp::Divide if right.iter().any(|(v, _)| *v == 0.0) => {
+                    return Err(WakeBotError::roll(
+                        RollErrorKind::DivideByZero,
+                        span.clone(),
+                    ))
+                }
+                BinaryOp::Divide => combine(&left, &right, budget, |a, b| a / b),
+            })
+        }
+    }
+}
+
+fn dice_count(expr: &Expr) -> usize {
+    match expr {
+        Expr::Number(_) => 0,
+        Expr::Dice(term) => term.count,
+        Expr::Negate(inner) => dice_count(inner),
+        Expr::Binary(_, left, right, _) => dice_count(left) + dice_count(right),
+    }
+}
+
+// Estimates the distribution by rolling many times, with fewer samples for bigger rolls
+fn simulated_distribution(expr: &Expr) -> Result<(Distribution, usize), WakeBotError> {
+    let samples = (MAX_SIMULATED_DICE / dice_count(expr).max(1)).clamp(MIN_SAMPLES, MAX_SAMPLES);
+    let options = RollOptions::default();
+    let chance = 1.0 / samples as f64;
+    let mut rng = rand::thread_rng();
+    let mut pairs = Vec::with_capacity(samples);
+    for _ in 0..samples {
+        let total = evaluate(expr, &options, &mut vec![], &mut rng)?;
+        pairs.push((total, chance));
+    }
+    Ok((normalize(pairs), samples))
+}
+
+pub fn roll_stats(input: &str) -> Result<RollStats, WakeBotError> {
+    let original = input;
+    let input = input.strip_prefix('!').unwrap_or(input).trim_start();
+    let offset = original.len() - input.len();
+    let (input, _) = split_label(input);
+    let (roll_str, d20_mode) = split_d20_mode(input);
+    // Spans in errors point into the text that was passed in, not just the expression
+    let shift = |e: WakeBotError| e.offset_by(offset);
+    let mut expr = parse_rolls(roll_str).map_err(shift)?;
+    if let Some(mode) = d20_mode {
+        apply_d20_options(&mut expr, mode, false);
+    }
+    let mut budget = MAX_EXACT_WORK;
+    let (distribution, exact, samples) =
+        match exact_distribution(&expr, &mut budget).map_err(shift)? {
+            Some(distribution) => (distribution, true, 0),
+            None => {
+                let (distribution, samples) = simulated_distribution(&expr).map_err(shift)?;
+                (distribution, false, samples)
+            }
+        };
+    let mean = distribution.iter().map(|(v, p)| v * p).sum::<f64>();
+    let variance = distribution
+        .iter()
+        .map(|(v, p)| (v - mean).powi(2) * p)
+        .sum::<f64>();
+    let min = distribution.first().map_or(0.0, |(v, _)| *v);
+    let max = distribution.last().map_or(0.0, |(v, _)| *v);
+    Ok(RollStats {
+        distribution,
+        exact,
+        samples,
+        mean,
+        std_dev: variance.sqrt(),
+        min,
+        max,
+    })
+}
+
+fn format_number(n: f64) -> String {
+    if n.fract() == 0.0 {
+        format!("{}", n)
+    } else {
+        format!("{:.2}", n)
+    }
+}
+
+// One row per total when there are few enough, otherwise totals are grouped into equal ranges
+fn format_histogram(stats: &RollStats) -> String {
+    let rows: Vec<(String, f64)> = if stats.distribution.len() <= HISTOGRAM_ROWS {
+        stats
+            .distribution
+            .iter()
+            .map(|(v, p)| (format_number(*v), *p))
+            .collect()
+    } else {
+        // Each range covers the same number of whole totals, apart from the last which stops at the max.
+        // Totals with fractions, like from division, are split into equal ranges that meet end to end.
+        let whole = stats.distribution.iter().all(|(v, _)| v.fract() == 0.0);
+        let (width, rows) = if whole {
+            let span = stats.max - stats.min + 1.0;
+            let width = (span / HISTOGRAM_ROWS as f64).ceil();
+            (width, (span / width).ceil() as usize)
+        } else {
+            (
+                (stats.max - stats.min) / HISTOGRAM_ROWS as f64,
+                HISTOGRAM_ROWS,
+            )
+        };
+        let mut buckets = vec![0.0; rows];
+        for (v, p) in stats.distribution.iter() {
+            let index = (((v - stats.min) / width) as usize).min(rows - 1);
+            buckets[index] += p;
+        }
+        buckets
+            .into_iter()
+            .enumerate()
+            .map(|(i, p)| {
+                let low = stats.min + width * i as f64;
+                let high = if whole {
+                    (low + width - 1.0).min(stats.max)
+                } else {
+                    low + width
+                };
+                (format!("{}-{}", format_number(low), format_number(high)), p)
+            })
+            .collect()
+    };
+    let label_width = rows.iter().map(|(label, _)| label.len()).max().unwrap_or(0);
+    let highest = rows.iter().map(|(_, p)| *p).fold(0.0, f64::max);
+    rows.iter()
+        .map(|(label, p)| {
+            let bar = "#".repeat((p / highest * HISTOGRAM_WIDTH).round() as usize);
+            format!(
+                "{:>width$} | {:<bar_width$} {:5.1}%",
+                label,
+                bar,
+                p * 100.0,
+                width = label_width,
+                bar_width = HISTOGRAM_WIDTH as usize
+            )
+        })
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+pub fn format_stats(roll: &str, stats: &RollStats, target: Option<&Target>) -> String {
+    let method = if stats.exact {
+        String::from("exact")
+    } else {
+        format!("estimated from {} rolls", stats.samples)
+    };
+    let mut result = format!(
+        "**{}** ({})\nMean: {:.2}, SD: {:.2}, Min: {}, Max: {}\n",
+        roll,
+        method,
+        stats.mean,
+        stats.std_dev,
+        format_number(stats.min),
+        format_number(stats.max)
+    );
+    if let Some(target) = target {
+        result += &format!(
+            "P(total {} {}): **{:.1}%**\n",
+            target.symbol(),
+            format_number(target.value),
+            stats.probability(target) * 100.0
+        );
+    }
+    result += "```\n";
+    result += &format_histogram(stats);
+    result += "\n```";
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn probability_of(stats: &RollStats, total: f64) -> f64 {
+        stats
+            .distribution
+            .iter()
+            .find(|(v, _)| *v == total)
+            .map_or(0.0, |(_, p)| *p)
+    }
+
+    fn assert_close(a: f64, b: f64) {
+        assert!((a - b).abs() < 1e-9, "{} != {}", a, b);
+    }
+
+    #[test]
+    fn single_die_is_uniform() {
+        let stats = roll_stats("1d6").unwrap();
+        assert!(stats.exact);
+        assert_eq!(stats.distribution.len(), 6);
+        for (_, p) in stats.distribution.iter() {
+            assert_close(*p, 1.0 / 6.0);
+        }
+        assert_close(stats.mean, 3.5);
+    }
+
+    #[test]
+    fn two_dice_peak_in_the_middle() {
+        let stats = roll_stats("2d6").unwrap();
+        assert_eq!((stats.min, stats.max), (2.0, 12.0));
+        assert_close(probability_of(&stats, 7.0), 6.0 / 36.0);
+        assert_close(probability_of(&stats, 2.0), 1.0 / 36.0);
+        let peak =
+            stats.distribution.iter().fold(
+                (0.0, 0.0),
+                |best, &(v, p)| if p > best.1 { (v, p) } else { best },
+            );
+        assert_eq!(peak.0, 7.0);
+    }
+
+    #[test]
+    fn advantage_favours_high_rolls() {
+        let stats = roll_stats("1d20 adv").unwrap();
+        for total in 1..=20 {
+            assert_close(
+                probability_of(&stats, total as f64),
+                (2 * total - 1) as f64 / 400.0,
+            );
+        }
+        assert_close(stats.mean, 13.825);
+    }
+
+    #[test]
+    fn histogram_ranges_are_equal_width() {
+        let stats = RollStats {
+            distribution: (1..=45).map(|v| (v as f64, 1.0 / 45.0)).collect(),
+            exact: true,
+            samples: 0,
+            mean: 23.0,
+            std_dev: 0.0,
+            min: 1.0,
+            max: 45.0,
+        };
+        let labels = format_histogram(&stats)
+            .lines()
+            .map(|line| String::from(line.split(" | ").next().unwrap().trim()))
+            .collect::<Vec<String>>();
+        assert_eq!(labels.first().unwrap(), "1-3");
+        assert_eq!(labels[1], "4-6");
+        assert_eq!(labels.last().unwrap(), "43-45");
+        assert_eq!(labels.len(), 15);
+    }
+}
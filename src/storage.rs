@@ -0,0 +1,448 @@
+use crate::errors::WakeBotError;
+use crate::luck::LuckStats;
+use async_trait::async_trait;
+use aws_sdk_dynamodb::{
+    error::SdkError,
+    operation::{
+        create_table::CreateTableError, delete_item::DeleteItemError, get_item::GetItemError,
+        put_item::PutItemError, scan::ScanError, update_item::UpdateItemError,
+    },
+};
+use std::fmt;
+
+// Where an action lives, which decides who can use it
+#[derive(Debug, Clone, PartialEq)]
+pub enum ActionScope {
+    // Shared by everyone in a server. Direct messages count as a server of their own, "dm".
+    Server { guild: String },
+    // Only usable by one user in one server, taking priority over a server action with the same name
+    Personal { guild: String, user_id: String },
+    // Saved before actions were split up by server, so shared everywhere and owned by nobody
+    Global,
+}
+
+impl ActionScope {
+    // Action names can't contain ':', so these keys can't clash with each other or with other stored data
+    pub fn key(&self, name: &str) -> String {
+        match self {
+            ActionScope::Server { guild } => format!("action:{}:{}", guild, name),
+            ActionScope::Personal { guild, user_id } => {
+                format!("action:{}:user:{}:{}", guild, user_id, name)
+            }
+            ActionScope::Global => String::from(name),
+        }
+    }
+
+    // Lower is checked first when looking an action up by name
+    fn precedence(&self) -> u8 {
+        match self {
+            ActionScope::Personal { .. } => 0,
+            ActionScope::Server { .. } => 1,
+            ActionScope::Global => 2,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Action {
+    pub name: String,
+    pub roll: String,
+    pub scope: ActionScope,
+    // The user who saved the action. Empty for global actions, which predate owners.
+    pub owner_id: String,
+}
+
+impl Action {
+    pub fn new(name: &str, roll: &str, scope: ActionScope, owner_id: &str) -> Self {
+        Action {
+            name: String::from(name),
+            // Remove prepended ! as we want to get rid of those
+            roll: String::from(roll.strip_prefix('!').unwrap_or(roll)),
+            scope,
+            owner_id: String::from(owner_id),
+        }
+    }
+
+    // Only the owner can change or delete an action. Global actions have no owner, so they can't be changed.
+    pub fn can_modify(&self, user_id: &str) -> bool {
+        !self.owner_id.is_empty() && self.owner_id == user_id
+    }
+}
+
+// Every roll is logged so it can be checked later with !verify
+#[derive(Debug, Clone)]
+pub struct RollRecord {
+    pub id: String,
+    pub expression: String,
+    pub user_id: String,
+    pub user_name: String,
+    pub channel_id: String,
+    // Unix timestamp of the message that asked for the roll
+    pub timestamp: i64,
+    pub result: String,
+    // Seed the dice were rolled from, so the roll can be replayed
+    pub seed: u64,
+    // Rolled with --private, so only the roller can see the result
+    pub is_private: bool,
+}
+
+#[derive(std::fmt::Debug)]
+pub enum WakeBotDbError {
+    AWSGetError(SdkError<GetItemError>),
+    AWSPutError(SdkError<PutItemError>),
+    AWSDeleteError(SdkError<DeleteItemError>),
+    AWSScanError(SdkError<ScanError>),
+    AWSUpdateError(SdkError<UpdateItemError>),
+    AWSCreateTableError(SdkError<CreateTableError>),
+    SqliteError(rusqlite::Error),
+    NotFound(WakeBotError),
+    // Something with the same key was already stored and was left as it was
+    AlreadyExists,
+}
+
+impl fmt::Display for WakeBotDbError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WakeBotDbError::AWSGetError(e) => write!(f, "{}", e),
+            WakeBotDbError::AWSPutError(e) => write!(f, "{}", e),
+            WakeBotDbError::AWSDeleteError(e) => write!(f, "{}", e),
+            WakeBotDbError::AWSScanError(e) => write!(f, "{}", e),
+            WakeBotDbError::AWSUpdateError(e) => write!(f, "{}", e),
+            WakeBotDbError::AWSCreateTableError(e) => write!(f, "{}", e),
+            WakeBotDbError::SqliteError(e) => write!(f, "{}", e),
+            WakeBotDbError::NotFound(e) => write!(f, "{}", e),
+            WakeBotDbError::AlreadyExists => write!(f, "Already exists."),
+        }
+    }
+}
+
+impl From<rusqlite::Error> for WakeBotDbError {
+    fn from(e: rusqlite::Error) -> Self {
+        WakeBotDbError::SqliteError(e)
+    }
+}
+
+// Everything the bot keeps between restarts. Crit profile scopes look like "user:<id>" or "channel:<id>",
+// and luck stat scopes like "<guild id>:<user id>" or "dm:<user id>".
+#[async_trait]
+pub trait Storage: Send + Sync {
+    async fn get_scoped_action(
+        &self,
+        scope: &ActionScope,
+        action_name: &str,
+    ) -> Result<Action, WakeBotDbError>;
+    async fn add_or_update_action(&self, action: &Action) -> Result<(), WakeBotDbError>;
+    async fn delete_action(
+        &self,
+        scope: &ActionScope,
+        action_name: &str,
+    ) -> Result<(), WakeBotDbError>;
+
+    // Finds the action a user gets when they use a name: their own first, then their server's, then
+    // any global action from before actions were split up
+    async fn get_action(
+        &self,
+        guild: &str,
+        user_id: &str,
+        action_name: &str,
+    ) -> Result<Action, WakeBotDbError> {
+        let scopes = [
+            ActionScope::Personal {
+                guild: String::from(guild),
+                user_id: String::from(user_id),
+            },
+            ActionScope::Server {
+                guild: String::from(guild),
+            },
+            ActionScope::Global,
+        ];
+        for scope in scopes.iter() {
+            match self.get_scoped_action(scope, action_name).await {
+                Err(WakeBotDbError::NotFound(_)) => continue,
+                result => return result,
+            }
+        }
+        Err(action_not_found())
+    }
+
+    // Every action a user can see in a server: their personal ones, the server's and any global ones.
+    // The same name can come back more than once from different scopes.
+    async fn list_actions(&self, guild: &str, user_id: &str)
+        -> Result<Vec<Action>, WakeBotDbError>;
+
+    // The actions a user would actually get by name, sorted by name, leaving out any that are shadowed
+    async fn get_visible_actions(
+        &self,
+        guild: &str,
+        user_id: &str,
+    ) -> Result<Vec<Action>, WakeBotDbError> {
+        let mut actions = self.list_actions(guild, user_id).await?;
+        actions.sort_by(|a, b| {
+            a.name
+                .cmp(&b.name)
+                .then(a.scope.precedence().cmp(&b.scope.precedence()))
+        });
+        actions.dedup_by(|a, b| a.name == b.name);
+        Ok(actions)
+    }
+
+    // Adds one to a named counter such as "heh", starting from zero, and returns the new count
+    async fn increment_counter(&self, name: &str) -> Result<i32, WakeBotDbError>;
+
+    async fn get_crit_profile(&self, scope: &str) -> Result<Option<String>, WakeBotDbError>;
+    async fn set_crit_profile(&self, scope: &str, profile: &str) -> Result<(), WakeBotDbError>;
+
+    // Records are never overwritten, so this gives AlreadyExists if the ID is taken
+    async fn add_roll_record(&self, record: &RollRecord) -> Result<(), WakeBotDbError>;
+    async fn get_roll_record(&self, id: &str) -> Result<RollRecord, WakeBotDbError>;
+
+    // Values a user has set with !set for their rolls in one server, such as a character's modifiers
+    async fn get_variable(
+        &self,
+        guild: &str,
+        user_id: &str,
+        name: &str,
+    ) -> Result<Option<String>, WakeBotDbError>;
+    async fn set_variable(
+        &self,
+        guild: &str,
+        user_id: &str,
+        name: &str,
+        value: &str,
+    ) -> Result<(), WakeBotDbError>;
+    async fn delete_variable(
+        &self,
+        guild: &str,
+        user_id: &str,
+        name: &str,
+    ) -> Result<(), WakeBotDbError>;
+    // Every variable a user has set in a server as names and values, sorted by name
+    async fn list_variables(
+        &self,
+        guild: &str,
+        user_id: &str,
+    ) -> Result<Vec<(String, String)>, WakeBotDbError>;
+
+    // Users that have never rolled get empty stats rather than an error
+    async fn get_luck_stats(&self, scope: &str) -> Result<LuckStats, WakeBotDbError>;
+    // Adds to a user's running totals in one step, so rolls made at the same time can't lose each other's
+    // dice. The stored name is replaced with the given one.
+    async fn add_luck_stats(
+        &self,
+        scope: &str,
+        user_name: &str,
+        stats: &LuckStats,
+    ) -> Result<(), WakeBotDbError>;
+    // Everyone's stats whose scope starts with the given prefix, e.g. every user in a guild.
+    // Returns each user's name alongside their stats.
+    async fn get_all_luck_stats(
+        &self,
+        prefix: &str,
+    ) -> Result<Vec<(String, LuckStats)>, WakeBotDbError>;
+}
+
+pub fn action_not_found() -> WakeBotDbError {
+    WakeBotDbError::NotFound(WakeBotError::new("Action does not exist."))
+}
+
+pub fn roll_not_found() -> WakeBotDbError {
+    WakeBotDbError::NotFound(WakeBotError::new("Roll does not exist."))
+}
+
+// Checks that every backend has to pass, run against each one from its own tests
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::luck::DieStats;
+
+    pub fn server() -> ActionScope {
+        ActionScope::Server {
+            guild: String::from("guild"),
+        }
+    }
+
+    fn personal(user_id: &str) -> ActionScope {
+        ActionScope::Personal {
+            guild: String::from("guild"),
+            user_id: String::from(user_id),
+        }
+    }
+
+    pub async fn actions_follow_scope_precedence(storage: &dyn Storage) {
+        storage
+            .add_or_update_action(&Action::new("attack", "!1d20+5", server(), "alice"))
+            .await
+            .unwrap();
+        storage
+            .add_or_update_action(&Action::new("attack", "1d20+7", personal("bob"), "bob"))
+            .await
+            .unwrap();
+        let action = storage
+            .get_action("guild", "alice", "attack")
+            .await
+            .unwrap();
+        assert_eq!((action.roll.as_str(), action.scope), ("1d20+5", server()));
+        let action = storage.get_action("guild", "bob", "attack").await.unwrap();
+        assert_eq!(
+            (action.roll.as_str(), action.scope),
+            ("1d20+7", personal("bob"))
+        );
+        assert!(matches!(
+            storage.get_action("other", "alice", "attack").await,
+            Err(WakeBotDbError::NotFound(_))
+        ));
+
+        let visible = storage.get_visible_actions("guild", "bob").await.unwrap();
+        assert_eq!(visible.len(), 1);
+        assert_eq!(visible[0].scope, personal("bob"));
+        assert_eq!(storage.list_actions("guild", "bob").await.unwrap().len(), 2);
+
+        storage
+            .delete_action(&personal("bob"), "attack")
+            .await
+            .unwrap();
+        let action = storage.get_action("guild", "bob", "attack").await.unwrap();
+        assert_eq!(action.owner_id, "alice");
+        assert!(action.can_modify("alice"));
+        assert!(!action.can_modify("bob"));
+        // Nobody owns a global action, so nobody can change it
+        let global = Action::new("old", "1d6", ActionScope::Global, "");
+        assert!(!global.can_modify(""));
+    }
+
+    pub async fn counters_count_up(storage: &dyn Storage) {
+        assert_eq!(storage.increment_counter("heh").await.unwrap(), 1);
+        assert_eq!(storage.increment_counter("heh").await.unwrap(), 2);
+        assert_eq!(storage.increment_counter("other").await.unwrap(), 1);
+    }
+
+    pub async fn crit_profiles_are_replaced(storage: &dyn Storage) {
+        assert_eq!(storage.get_crit_profile("user:1").await.unwrap(), None);
+        storage.set_crit_profile("user:1", "19").await.unwrap();
+        storage.set_crit_profile("user:1", "max").await.unwrap();
+        assert_eq!(
+            storage.get_crit_profile("user:1").await.unwrap().as_deref(),
+            Some("max")
+        );
+    }
+
+    pub async fn roll_records_are_never_overwritten(storage: &dyn Storage) {
+        let record = RollRecord {
+            id: String::from("abcd2345"),
+            expression: String::from("1d20"),
+            user_id: String::from("1"),
+            user_name: String::from("alice"),
+            channel_id: String::from("2"),
+            timestamp: 1_700_000_000,
+            result: String::from("1d20 (20 -> 20) = 20"),
+            seed: u64::MAX,
+            is_private: true,
+        };
+        storage.add_roll_record(&record).await.unwrap();
+        let overwrite = RollRecord {
+            result: String::from("1d20 (1 -> 1) = 1"),
+            ..record.clone()
+        };
+        assert!(matches!(
+            storage.add_roll_record(&overwrite).await,
+            Err(WakeBotDbError::AlreadyExists)
+        ));
+        let stored = storage.get_roll_record("abcd2345").await.unwrap();
+        assert_eq!(stored.result, record.result);
+        assert_eq!(stored.seed, u64::MAX);
+        assert!(stored.is_private);
+        assert!(matches!(
+            storage.get_roll_record("missing").await,
+            Err(WakeBotDbError::NotFound(_))
+        ));
+    }
+
+    pub async fn luck_stats_add_up(storage: &dyn Storage) {
+        let mut stats = LuckStats::default();
+        stats.dice.insert(
+            20,
+            DieStats {
+                rolled: 3,
+                total: 40,
+                highest: 1,
+                lowest: 0,
+            },
+        );
+        assert_eq!(
+            storage.get_luck_stats("guild:1").await.unwrap(),
+            LuckStats::default()
+        );
+        storage
+            .add_luck_stats("guild:1", "alice", &stats)
+            .await
+            .unwrap();
+        let mut more = LuckStats::default();
+        more.dice.insert(
+            6,
+            DieStats {
+                rolled: 2,
+                total: 7,
+                highest: 1,
+                lowest: 1,
+            },
+        );
+        more.dice.insert(20, stats.dice[&20].clone());
+        storage
+            .add_luck_stats("guild:1", "alice", &more)
+            .await
+            .unwrap();
+        storage
+            .add_luck_stats("guild:2", "bob", &stats)
+            .await
+            .unwrap();
+        storage
+            .add_luck_stats("guild2:3", "carol", &stats)
+            .await
+            .unwrap();
+        let mut total = stats.clone();
+        total.merge(&more);
+        assert_eq!(storage.get_luck_stats("guild:1").await.unwrap(), total);
+        let mut users = storage.get_all_luck_stats("guild:").await.unwrap();
+        users.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(
+            users,
+            vec![(String::from("alice"), total), (String::from("bob"), stats)]
+        );
+    }
+
+    pub async fn variables_belong_to_one_user(storage: &dyn Storage) {
+        storage
+            .set_variable("guild", "1", "str_mod", "3")
+            .await
+            .unwrap();
+        storage
+            .set_variable("guild", "1", "prof", "2")
+            .await
+            .unwrap();
+        storage
+            .set_variable("guild", "1", "str_mod", "4")
+            .await
+            .unwrap();
+        assert_eq!(
+            storage
+                .get_variable("guild", "1", "str_mod")
+                .await
+                .unwrap()
+                .as_deref(),
+            Some("4")
+        );
+        assert_eq!(
+            storage.get_variable("guild", "2", "str_mod").await.unwrap(),
+            None
+        );
+        assert_eq!(
+            storage.list_variables("guild", "1").await.unwrap(),
+            vec![
+                (String::from("prof"), String::from("2")),
+                (String::from("str_mod"), String::from("4"))
+            ]
+        );
+        storage.delete_variable("guild", "1", "prof").await.unwrap();
+        assert_eq!(storage.list_variables("guild", "1").await.unwrap().len(), 1);
+    }
+}